@@ -18,7 +18,7 @@ fn help(state: &mut State, _args: &[&str]) -> color_eyre::Result<()> {
 
 
     for (cmd_str, cmd) in &state.commands {
-        state.printer.print(format!("{:max_len$}  -\t{}", cmd_str, cmd.description).into())?;
+        state.printer.print(format!("{:max_len$}  -\t{}", cmd_str, cmd.description))?;
     }
 
     Ok(())
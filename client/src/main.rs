@@ -1,39 +1,82 @@
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use clap::Parser;
-use hickory_client::{
-    client::{Client, ClientHandle},
-    proto::{
-        rr::{DNSClass, IntoName, RecordType},
-        runtime::TokioRuntimeProvider,
-        udp::UdpClientStream,
-    },
-};
+use hickory_client::proto::rr::{Name, RData, RecordType};
 use color_eyre::Result;
-use inquire::Confirm;
+use inquire::{Confirm, Password};
 use reedline::{ExternalPrinter, Reedline, Signal};
 use tokio::select;
-use tokio::sync::{mpsc::Sender, Mutex};
-use shared::{encrypt, print_banner, Action, State};
+use shared::{
+    capacity, encode_packet, new_session_id, print_banner, probe_name,
+    Action, Cipher, Compression, ConnectPassword, DownstreamKind, EncodingKind, Handshake, Keys, Packet, Psk, ServerHello, State,
+    CHALLENGE_LEN, SEND_WINDOW,
+};
 use crate::{
     commands::init_commands,
     prompt::NihilPrompt,
+    transport::{parse_resolver, system_resolvers, Transport, TransportKind, EDNS_PAYLOAD},
+    tunnel::Tunnel,
 };
 
 mod commands;
 mod prompt;
+mod tls;
+mod transport;
+mod tunnel;
 
 #[derive(Parser)]
 pub struct CliArgs {
     /// Target Address
     #[arg(short, long, value_parser, default_value = "127.0.0.1")]
     address: IpAddr,
-    /// Target Port
-    #[arg(short, long, value_parser, default_value = "5053")]
-    port: u16,
-    /// XOR Key used to encrypt data
-    #[arg(long, default_value = "sisyphean")]
-    xor_key: String,
+    /// Target Port, 5053 for udp and 853 for dot when omitted
+    #[arg(short, long, value_parser)]
+    port: Option<u16>,
+    /// How queries reach the server (udp, dot)
+    #[arg(long, value_enum, default_value = "udp")]
+    transport: TransportKind,
+    /// SHA-256 fingerprint of the server's DoT certificate, the server prints it on startup
+    #[arg(long, required_if_eq("transport", "dot"))]
+    tls_pin: Option<String>,
+    /// Go through a recursive resolver instead of straight to the server, the ones in /etc/resolv.conf when no address is given.
+    /// Queries move on to the next of those whenever one times out or answers SERVFAIL.
+    /// The --domain zone has to be delegated to the server
+    #[arg(long, num_args = 0..=1, value_parser = parse_resolver, conflicts_with = "tls_pin")]
+    via_resolver: Option<Option<SocketAddr>>,
+    /// Times a query is sent again after it times out or the resolver gives up on it
+    #[arg(long, default_value = "3")]
+    retries: usize,
+    /// Milliseconds a query waits for its answer before it's sent again, at most 5000
+    #[arg(long, default_value = "5000")]
+    timeout: u64,
+    /// EDNS0 UDP payload size advertised to the server, which packs downstream answers up to it (at least 512)
+    #[arg(long, default_value_t = EDNS_PAYLOAD)]
+    edns_payload: u16,
+    /// Compression offered to the server (deflate, none), messages go out uncompressed whenever it doesn't help
+    #[arg(long, default_value = "deflate")]
+    compression: Compression,
+    /// Data queries kept in flight at once while sending a message
+    #[arg(long, default_value_t = SEND_WINDOW)]
+    window: usize,
+    /// Shared secret that authenticates the key exchange
+    #[arg(long, alias = "xor-key", default_value = "sisyphean")]
+    secret: String,
+    /// Label encoding (base32, base36, hex, base64url), probes for the densest one that survives when omitted
+    #[arg(short, long)]
+    encoding: Option<EncodingKind>,
+    /// Record type data comes back from the server in (txt, cname, mx, aaaa, null)
+    #[arg(long, default_value = "txt")]
+    downstream: DownstreamKind,
+    /// Milliseconds between polls for queued server messages while data is flowing
+    #[arg(long, default_value = "1000")]
+    poll_interval: u64,
+    /// Milliseconds the poll interval backs off to while the server has nothing queued
+    #[arg(long, default_value = "10000")]
+    poll_max_interval: u64,
+    /// Tunnel zone the server is authoritative for (e.g. t.example.lab), has to match the server's
+    #[arg(short, long)]
+    domain: Option<String>,
 }
 
 #[tokio::main]
@@ -48,13 +91,14 @@ async fn main() -> Result<()> {
     let event_printer = printer.clone();
 
     let app_state = State::new(commands, &printer, sender.clone());
+    let psk = Psk::from_secret(&cli_args.secret);
+    let domain = cli_args.domain.clone().unwrap_or_default();
 
-    let addr = SocketAddr::new(
-        cli_args.address,
-        cli_args.port,
-    );
-    let conn = UdpClientStream::builder(addr, TokioRuntimeProvider::default()).build();
-
+    let addrs = upstreams(&cli_args)?;
+    let via_resolver = cli_args.via_resolver.is_some();
+    if via_resolver && domain.is_empty() {
+        return Err(color_eyre::eyre::eyre!("--via-resolver needs the --domain delegated to the server"));
+    }
     let mut line_editor = Reedline::create()
         .with_external_printer(printer);
     let mut prompt = NihilPrompt::new(app_state);
@@ -62,12 +106,50 @@ async fn main() -> Result<()> {
     print_banner("Client");
     // TODO make DNS it's own command (with subcommands)
 
-    let (client, bg) = Client::connect(conn).await?;
-    tokio::spawn(bg);
+    let client = Arc::new(connect(&cli_args, &addrs).await?);
+
+    let encoding = match cli_args.encoding {
+        // Resolvers are free to change the case of names, see RFC 4343 and 0x20 randomization
+        Some(encoding) if via_resolver && encoding.encoding().case_sensitive() => {
+            return Err(color_eyre::eyre::eyre!("{} does not survive the case changes resolvers make, pick another encoding", encoding));
+        }
+        Some(encoding) => encoding,
+        None => negotiate_encoding(&client, &domain, via_resolver).await?,
+    };
+    prompt.state.printer.print(format!("Using {} encoding, {} bytes per query", encoding, capacity(&domain, encoding.encoding())))?;
+
+    let session_id = new_session_id();
+    let (keys, server_hello) = handshake(&client, &psk, session_id, cli_args.compression, encoding, &domain).await?;
+    prompt.state.printer.print(format!("Session {:08x} established, {} compression", session_id, server_hello.compression))?;
+
+    if let Some(challenge) = server_hello.challenge {
+        let password = tokio::task::spawn_blocking(|| {
+            Password::new("Server requires a password:")
+                .without_confirmation()
+                .prompt()
+        }).await??;
+        authenticate(&client, &keys.upstream, &ConnectPassword::new(&password), &challenge, session_id, encoding, &domain).await?;
+        prompt.state.printer.print("Password accepted".to_string())?;
+    }
+
+    let tunnel = Arc::new(Tunnel::new(client, sender, session_id, keys, encoding, cli_args.downstream, domain)
+        .with_window(cli_args.window)
+        .with_compression(server_hello.compression));
 
-    let client = Arc::new(Mutex::new(client));
+    let poll_tunnel = tunnel.clone();
+    let poll_handle = tokio::spawn(async move {
+        poll_tunnel.poll_loop(
+            Duration::from_millis(cli_args.poll_interval),
+            Duration::from_millis(cli_args.poll_max_interval),
+        ).await
+    });
 
-    prompt.state.printer.print(format!("Ready and waiting to shoot queries at: {}", addr).into())?;
+    if via_resolver {
+        let resolvers = addrs.iter().map(SocketAddr::to_string).collect::<Vec<_>>().join(", ");
+        prompt.state.printer.print(format!("Ready and waiting to shoot queries through the resolvers at: {}", resolvers))?;
+    } else {
+        prompt.state.printer.print(format!("Ready and waiting to shoot queries at: {}", addrs[0]))?;
+    }
 
     let reedline_handle = tokio::task::spawn_blocking(move || -> Result<()> {
         while !prompt.state.exit {
@@ -86,12 +168,11 @@ async fn main() -> Result<()> {
             match sig {
                 Ok(Signal::Success(buffer)) => {
                     let args = buffer.split_whitespace().collect::<Vec<&str>>();
-                    if !args.is_empty() {
-                        if let Some(cmd) = prompt.state.commands.get(args[0]) {
-                            (cmd.function)(&mut prompt.state, &args[1..])?
-                        }
+                    if let Some(name) = args.first()
+                        && let Some(cmd) = prompt.state.commands.get(name) {
+                        (cmd.function)(&mut prompt.state, &args[1..])?
                     }
-                    prompt.state.printer.print(format!("Processed: {}", buffer).into())?;
+                    prompt.state.printer.print(format!("Processed: {}", buffer))?;
                 },
                 Ok(Signal::CtrlD) => {
                     prompt.state.printer.print("\nAborted!".into())?;
@@ -121,18 +202,26 @@ async fn main() -> Result<()> {
                                         eprintln!("Failed to print log message: {}", e);
                                     }
                                 }
+                                Action::Received { message_id, data } => {
+                                    event_printer.print(format!("Message {} from server: {}", message_id, String::from_utf8_lossy(&data))).ok();
+                                }
+                                // Only the server reassembles client messages and manages sessions
+                                Action::Message { .. }
+                                | Action::Send { .. }
+                                | Action::ListClients
+                                | Action::Kick(_)
+                                | Action::Focus(_)
+                                | Action::Lookup { .. } => {}
                                 Action::TempSend => {
                                     event_printer.print("Sending test message...".into()).ok();
 
-                                    let client_clone = client.clone();
-                                    let sender_clone = sender.clone();
+                                    let tunnel_clone = tunnel.clone();
                                     let printer_clone = event_printer.clone();
 
-                                    let xor_key_clone = cli_args.xor_key.clone();
                                     tokio::spawn(async move {
-                                        if let Err(e) = send_test_message(client_clone, sender_clone, xor_key_clone).await {
+                                        if let Err(e) = tunnel_clone.send_message(TEST_MESSAGE.as_bytes()).await {
                                             let error_msg = format!("Error sending test message: {}", e);
-                                            printer_clone.print(error_msg.into()).ok();
+                                            printer_clone.print(error_msg).ok();
                                         } else {
                                             printer_clone.print("Test message sent successfully!".into()).ok();
                                         }
@@ -152,46 +241,104 @@ async fn main() -> Result<()> {
 
     reedline_handle.await.map_err(|e| color_eyre::eyre::eyre!("Reedline task failed: {}", e))??;
     event_handle.abort();
+    poll_handle.abort();
 
     Ok(())
 }
 
-async fn send_test_message(client: Arc<Mutex<Client>>, sender: Sender<Action>, xor_key: String) -> Result<()> {
-    let plaintext = "\"Did you ever hear the tragedy of Darth Plagueis The Wise? I thought not. It’s not a story the Jedi would tell you. It’s a Sith legend. Darth Plagueis was a Dark Lord of the Sith, so powerful and so wise he could use the Force to influence the midichlorians to create life… He had such a knowledge of the dark side that he could even keep the ones he cared about from dying. The dark side of the Force is a pathway to many abilities some consider to be unnatural. He became so powerful… the only thing he was afraid of was losing his power, which eventually, of course, he did. Unfortunately, he taught his apprentice everything he knew, then his apprentice killed him in his sleep. Ironic. He could save others from death, but not himself.\" - Darth Sidious.";
-
-    let b32_data = encrypt(plaintext.as_bytes(), xor_key.as_bytes())?;
-
-    sender.send(Action::Log(format!("Sending {} DNS queries...", b32_data.len()))).await.ok();
-
-    for (index, name) in b32_data.iter().enumerate() {
-        let domain_name = format!("{}", str::from_utf8(&name.as_bytes())?);
-        sender.send(Action::Log(format!("[{}/{}] Querying: {}", index + 1, b32_data.len(), domain_name))).await.ok();
-
-        let mut client_guard = client.lock().await;
-        let response = client_guard.query(
-            domain_name.into_name()?,
-            DNSClass::IN,
-            RecordType::A,
-        ).await?;
-
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-
-        if !response.answers().is_empty() {
-            sender.send(Action::Log(
-                format!("[{}/{}] Server echoed: {}",
-                        index + 1,
-                        b32_data.len(),
-                        response.answers()[0].to_string().to_uppercase()
-                )
-            )).await.ok();
-        } else {
-            sender.send(Action::Log(
-                format!("[{}/{}] No answer from server", index + 1, b32_data.len())
-            )).await.ok();
+const TEST_MESSAGE: &str = "\"Did you ever hear the tragedy of Darth Plagueis The Wise? I thought not. It’s not a story the Jedi would tell you. It’s a Sith legend. Darth Plagueis was a Dark Lord of the Sith, so powerful and so wise he could use the Force to influence the midichlorians to create life… He had such a knowledge of the dark side that he could even keep the ones he cared about from dying. The dark side of the Force is a pathway to many abilities some consider to be unnatural. He became so powerful… the only thing he was afraid of was losing his power, which eventually, of course, he did. Unfortunately, he taught his apprentice everything he knew, then his apprentice killed him in his sleep. Ironic. He could save others from death, but not himself.\" - Darth Sidious.";
+
+/// Where queries go, the resolvers when going through them and the server otherwise
+fn upstreams(cli_args: &CliArgs) -> Result<Vec<SocketAddr>> {
+    Ok(match cli_args.via_resolver {
+        Some(Some(resolver)) => vec![resolver],
+        Some(None) => system_resolvers()?,
+        None => vec![SocketAddr::new(
+            cli_args.address,
+            cli_args.port.unwrap_or(cli_args.transport.default_port()),
+        )],
+    })
+}
+
+/// Sets up the transport picked on the command line towards `addrs`
+async fn connect(cli_args: &CliArgs, addrs: &[SocketAddr]) -> Result<Transport> {
+    let client = match (cli_args.transport, &cli_args.tls_pin) {
+        // DoT goes straight to the server, never through a resolver
+        (TransportKind::Dot, Some(pin)) => Transport::connect_tls(addrs[0], pin, cli_args.retries).await?,
+        _ => Transport::connect(addrs, cli_args.retries).await?,
+    };
+
+    Ok(client
+        .with_edns_payload(cli_args.edns_payload)
+        .with_timeout(Duration::from_millis(cli_args.timeout)))
+}
+
+/// Builds a name straight from its labels so case-sensitive encodings don't go through IDNA lowercasing
+fn to_name(domain_name: &str) -> Result<Name> {
+    Ok(Name::from_labels(domain_name.split('.').map(str::as_bytes))?)
+}
+
+/// Sends a probe per encoding, densest first, and settles on the first one the server reads back intact.
+/// Through a resolver case-sensitive encodings are skipped, a lucky probe doesn't mean every later query keeps its case
+async fn negotiate_encoding(client: &Transport, domain: &str, via_resolver: bool) -> Result<EncodingKind> {
+    for kind in EncodingKind::ALL {
+        if via_resolver && kind.encoding().case_sensitive() {
+            continue;
         }
+
+        let response = client.query(to_name(&probe_name(kind, domain))?, RecordType::A).await;
+
+        if response.is_ok_and(|response| !response.answers().is_empty()) {
+            return Ok(kind);
+        }
+    }
+
+    Err(color_eyre::eyre::eyre!("Server accepted none of the probed encodings"))
+}
+
+/// Trades ephemeral keys with the server, both halves are authenticated with the pre-shared key.
+/// Also hands back the server's hello, which holds its password challenge and the compression it agreed to
+async fn handshake(client: &Transport, psk: &Psk, session_id: u32, compression: Compression, encoding: EncodingKind, domain: &str) -> Result<(Keys, ServerHello)> {
+    let handshake = Handshake::new();
+    let client_public = handshake.public();
+    let hello = Packet::Hello {
+        session_id,
+        public: client_public,
+        tag: psk.client_hello_tag(session_id, &client_public, compression.to_byte()),
+        compression: compression.to_byte(),
+    };
+
+    let response = client.query(to_name(&encode_packet(&hello, encoding.encoding(), domain)?)?, RecordType::AAAA).await?;
+
+    let records = response.answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            RData::AAAA(aaaa) => Some(aaaa.0.octets()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let server_hello = ServerHello::from_records(&records)?;
+    psk.verify_server_hello(session_id, &client_public, &server_hello.public, &server_hello.tagged_bytes(), &server_hello.tag)?;
+    if server_hello.compression != Compression::None && server_hello.compression != compression {
+        return Err(color_eyre::eyre::eyre!("Server picked {} compression, which wasn't offered", server_hello.compression));
     }
 
-    sender.send(Action::Log("All queries completed.".into())).await.ok();
+    Ok((handshake.finish(&client_public, &server_hello.public)?, server_hello))
+}
+
+/// Answers the server's challenge, the response is sealed so it can't be used to guess the password offline
+async fn authenticate(client: &Transport, cipher: &Cipher, password: &ConnectPassword, challenge: &[u8; CHALLENGE_LEN], session_id: u32, encoding: EncodingKind, domain: &str) -> Result<()> {
+    let response = password.respond(session_id, challenge);
+    let auth = Packet::Auth {
+        session_id,
+        sealed: cipher.seal(&response, &session_id.to_be_bytes()),
+    };
+
+    let response = client.query(to_name(&encode_packet(&auth, encoding.encoding(), domain)?)?, RecordType::A).await?;
+
+    if response.answers().is_empty() {
+        return Err(color_eyre::eyre::eyre!("Server refused the password"));
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
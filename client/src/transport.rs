@@ -0,0 +1,418 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use hickory_client::client::Client;
+use hickory_client::proto::op::{Edns, Message, Query, ResponseCode};
+use hickory_client::proto::rr::{Name, RecordType};
+use hickory_client::proto::runtime::TokioRuntimeProvider;
+use hickory_client::proto::rustls::tls_client_connect;
+use hickory_client::proto::tcp::TcpClientStream;
+use hickory_client::proto::udp::UdpClientStream;
+use hickory_client::proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, FirstAnswer};
+use rustls::pki_types::ServerName;
+use shared::new_nonce_label;
+use tokio::sync::Mutex;
+use crate::tls::pinned_config;
+
+/// How queries travel to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TransportKind {
+    /// Plain UDP, falling back to TCP for truncated answers
+    Udp,
+    /// DNS-over-TLS, the server certificate has to match --tls-pin
+    Dot,
+}
+
+impl TransportKind {
+    pub fn default_port(self) -> u16 {
+        match self {
+            TransportKind::Udp => 5053,
+            TransportKind::Dot => 853,
+        }
+    }
+}
+
+/// Recursive resolvers listen on the standard port
+const RESOLVER_PORT: u16 = 53;
+/// EDNS0 UDP payload size advertised unless told otherwise, small enough to dodge IP fragmentation
+pub const EDNS_PAYLOAD: u16 = 1232;
+/// How long an attempt waits for its answer unless told otherwise, hickory never waits longer than this anyway
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One server or resolver queries can go to
+struct Upstream {
+    addr: SocketAddr,
+    client: Client,
+    /// Only connected once the first truncated answer shows up
+    tcp: Mutex<Option<Client>>,
+}
+
+impl Upstream {
+    async fn udp(addr: SocketAddr) -> Result<Self> {
+        let conn = UdpClientStream::builder(addr, TokioRuntimeProvider::default()).build();
+        let (client, bg) = Client::connect(conn).await?;
+        tokio::spawn(bg);
+
+        Ok(Self {
+            addr,
+            client,
+            tcp: Mutex::new(None),
+        })
+    }
+}
+
+/// Queries the server over UDP or TLS, retrying over TCP whenever a UDP answer comes back truncated
+pub struct Transport {
+    /// Tried in turn, each failed attempt moves on to the next one
+    upstreams: Vec<Upstream>,
+    /// Upstream queries currently go to
+    current: AtomicUsize,
+    /// Times a query is sent again after timing out or coming back SERVFAIL, which resolvers do when the server is slow
+    retries: usize,
+    /// How long each attempt waits for its answer
+    timeout: Duration,
+    /// Largest UDP answer we tell the server we take, it sizes downstream data to fit
+    edns_payload: u16,
+}
+
+impl Transport {
+    /// Queries go over UDP to the first of `addrs`, moving on to the next whenever one times out or gives up
+    pub async fn connect(addrs: &[SocketAddr], retries: usize) -> Result<Self> {
+        if addrs.is_empty() {
+            return Err(eyre!("No address to send queries to"));
+        }
+
+        let mut upstreams = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            upstreams.push(Upstream::udp(*addr).await?);
+        }
+
+        Ok(Self {
+            upstreams,
+            current: AtomicUsize::new(0),
+            retries,
+            timeout: QUERY_TIMEOUT,
+            edns_payload: EDNS_PAYLOAD,
+        })
+    }
+
+    /// Connects over DNS-over-TLS, trusting only the certificate with the `pin` fingerprint
+    pub async fn connect_tls(addr: SocketAddr, pin: &str, retries: usize) -> Result<Self> {
+        let server_name = ServerName::IpAddress(addr.ip().into());
+        let (stream, handle) = tls_client_connect(addr, server_name, pinned_config(pin)?, TokioRuntimeProvider::default());
+        let (client, bg) = Client::new(stream, handle, None).await?;
+        tokio::spawn(bg);
+
+        Ok(Self {
+            upstreams: vec![Upstream {
+                addr,
+                client,
+                tcp: Mutex::new(None),
+            }],
+            current: AtomicUsize::new(0),
+            retries,
+            timeout: QUERY_TIMEOUT,
+            edns_payload: EDNS_PAYLOAD,
+        })
+    }
+
+    pub fn with_edns_payload(mut self, edns_payload: u16) -> Self {
+        self.edns_payload = edns_payload.max(512);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Longest a query can take before it's given up on, every one of its attempts timing out
+    pub fn deadline(&self) -> Duration {
+        self.timeout * (self.retries as u32 + 1)
+    }
+
+    /// Sends a query, asking again when it gets lost on the way. Every attempt goes out under a fresh cache-busting label,
+    /// which the server looks past, so a retry gets the same answer without a resolver serving it from its cache.
+    /// Attempts that time out or come back SERVFAIL move on to the next upstream, which later queries stick with
+    pub async fn query(&self, name: Name, query_type: RecordType) -> Result<DnsResponse> {
+        let mut attempt = 0;
+        loop {
+            let current = self.current.load(Ordering::Relaxed);
+            let upstream = &self.upstreams[current % self.upstreams.len()];
+            let attempt_result = tokio::time::timeout(self.timeout, self.query_once(upstream, name.prepend_label(new_nonce_label())?, query_type))
+                .await
+                .unwrap_or_else(|_| Err(eyre!("No answer within {} ms", self.timeout.as_millis())));
+            match attempt_result {
+                Ok(response) if response.response_code() != ResponseCode::ServFail => return Ok(response),
+                result if attempt >= self.retries => return result,
+                _ => {
+                    // Queries failing side by side only move on once
+                    let next = (current + 1) % self.upstreams.len();
+                    self.current.compare_exchange(current, next, Ordering::Relaxed, Ordering::Relaxed).ok();
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn query_once(&self, upstream: &Upstream, name: Name, query_type: RecordType) -> Result<DnsResponse> {
+        let response = upstream.client.send(self.request(name.clone(), query_type)).first_answer().await?;
+        if !response.truncated() {
+            return Ok(response);
+        }
+
+        let tcp = Self::tcp_client(upstream).await?;
+        Ok(tcp.send(self.request(name, query_type)).first_answer().await?)
+    }
+
+    /// A recursive query advertising our EDNS0 payload size
+    fn request(&self, name: Name, query_type: RecordType) -> DnsRequest {
+        let mut message = Message::query();
+        message.add_query(Query::query(name, query_type))
+            .set_recursion_desired(true);
+        message.extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .set_max_payload(self.edns_payload)
+            .set_version(0);
+
+        let mut options = DnsRequestOptions::default();
+        options.use_edns = true;
+        DnsRequest::new(message, options)
+    }
+
+    async fn tcp_client(upstream: &Upstream) -> Result<Client> {
+        let mut tcp = upstream.tcp.lock().await;
+        if let Some(client) = tcp.as_ref() {
+            return Ok(client.clone());
+        }
+
+        let (stream, handle) = TcpClientStream::new(upstream.addr, None, None, TokioRuntimeProvider::default());
+        let (client, bg) = Client::new(stream, handle, None).await?;
+        tokio::spawn(bg);
+
+        Ok(tcp.insert(client).clone())
+    }
+}
+
+/// Reads a resolver address as `ip` or `ip:port`, port 53 when left out
+pub fn parse_resolver(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, RESOLVER_PORT)))
+        .map_err(|_| format!("Invalid resolver address: {addr}"))
+}
+
+/// Every nameserver in /etc/resolv.conf, in the order given
+pub fn system_resolvers() -> Result<Vec<SocketAddr>> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    let resolvers = parse_resolv_conf(&resolv_conf);
+    if resolvers.is_empty() {
+        return Err(eyre!("No nameserver in /etc/resolv.conf"));
+    }
+
+    Ok(resolvers)
+}
+
+fn parse_resolv_conf(resolv_conf: &str) -> Vec<SocketAddr> {
+    resolv_conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        // Link-local IPv6 nameservers carry a %interface suffix
+        .filter_map(|addr| addr.trim().split('%').next()?.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, RESOLVER_PORT))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_client::proto::op::Header;
+    use hickory_client::proto::rr::rdata::{A, TXT};
+    use hickory_client::proto::xfer::Protocol;
+    use hickory_client::proto::rr::{RData, Record};
+    use hickory_server::ServerFuture;
+    use hickory_server::authority::MessageResponseBuilder;
+    use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+    use clap::Parser;
+    use rcgen::CertifiedKey;
+    use shared::cert_fingerprint;
+    use crate::{connect, upstreams, CliArgs};
+    use super::*;
+
+    const ANSWER: A = A::new(127, 0, 0, 1);
+
+    /// Answers every query with the same A record
+    struct Fixed;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Fixed {
+        async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+            let name = request.queries()[0].original().name().clone();
+            let answers = [Record::from_rdata(name, 0, RData::A(ANSWER))];
+            let response = MessageResponseBuilder::from_message_request(request)
+                .build(Header::response_from_request(request.header()), answers.iter(), vec![], vec![], vec![]);
+
+            response_handle.send_response(response).await.expect("response sent")
+        }
+    }
+
+    /// Answers every query with a TXT record too big for UDP, which only goes out whole over TCP
+    struct Oversized;
+
+    /// Character strings in the TXT record `Oversized` answers with
+    const OVERSIZED_STRINGS: usize = 16;
+
+    fn oversized_txt() -> TXT {
+        TXT::new((0..OVERSIZED_STRINGS).map(|index| format!("{index:x}").repeat(255)).collect())
+    }
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Oversized {
+        async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+            let name = request.queries()[0].original().name().clone();
+            let mut header = Header::response_from_request(request.header());
+            // Over UDP it goes out without the record and marked truncated, like a server that can't fit it would
+            let answers = match request.protocol() {
+                Protocol::Udp => {
+                    header.set_truncated(true);
+                    vec![]
+                }
+                _ => vec![Record::from_rdata(name, 0, RData::TXT(oversized_txt()))],
+            };
+            let response = MessageResponseBuilder::from_message_request(request)
+                .build(header, answers.iter(), vec![], vec![], vec![]);
+
+            response_handle.send_response(response).await.expect("response sent")
+        }
+    }
+
+    /// Plain UDP server on a loopback port
+    async fn udp_server() -> Result<(ServerFuture<Fixed>, SocketAddr)> {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        let mut server = ServerFuture::new(Fixed);
+        server.register_socket(socket);
+
+        Ok((server, addr))
+    }
+
+    /// UDP and TCP on the same loopback port, the way the server listens
+    async fn udp_and_tcp_server() -> Result<(ServerFuture<Oversized>, SocketAddr)> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mut server = ServerFuture::new(Oversized);
+        server.register_socket(tokio::net::UdpSocket::bind(addr).await?);
+        server.register_listener(listener, Duration::from_secs(10));
+
+        Ok((server, addr))
+    }
+
+    /// DoT server on a loopback port with a fresh self-signed certificate, written out and listened with the way the server does.
+    /// Hands back its address along with the fingerprint the server prints
+    async fn dot_server() -> Result<(ServerFuture<Fixed>, SocketAddr, String)> {
+        let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()])?;
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("transport-test-{}-{}.crt", std::process::id(), new_nonce_label()));
+        let key_path = cert_path.with_extension("key");
+        std::fs::write(&cert_path, cert.pem())?;
+        std::fs::write(&key_path, key_pair.serialize_pem())?;
+
+        let mut server = ServerFuture::new(Fixed);
+        let any_port = "127.0.0.1:0".parse()?;
+        let listening = server::listen(&mut server, any_port, Some((any_port, &cert_path, &key_path))).await;
+        std::fs::remove_file(&cert_path).ok();
+        std::fs::remove_file(&key_path).ok();
+        let (addr, fingerprint) = listening?.tls.expect("a DoT listener");
+        assert_eq!(fingerprint, cert_fingerprint(cert.der()));
+
+        Ok((server, addr, fingerprint))
+    }
+
+    /// Connects the way the client does when started with `--transport dot`
+    async fn dot_client(addr: SocketAddr, pin: &str) -> Result<Transport> {
+        let port = addr.port().to_string();
+        let cli_args = CliArgs::try_parse_from(["client", "--transport", "dot", "--port", &port, "--tls-pin", pin, "--retries", "0"])?;
+
+        connect(&cli_args, &upstreams(&cli_args)?).await
+    }
+
+    #[test]
+    fn resolv_conf_gives_every_nameserver() {
+        let resolv_conf = "# generated\nsearch lab\nnameserver 10.0.0.1\n nameserver fe80::1%eth0\nnameserver bogus\nnameserver 10.0.0.2\n";
+
+        assert_eq!(parse_resolv_conf(resolv_conf), [
+            "10.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            "[fe80::1]:53".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_upstream() -> Result<()> {
+        let (_server, addr) = udp_server().await?;
+        // Nothing answers on a port that was just bound and dropped
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+
+        let transport = Transport::connect(&[dead, addr], 1).await?;
+        let response = transport.query(Name::from_ascii("probe.t.example.lab.")?, RecordType::A).await?;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        // Later queries stay with the upstream that answered
+        let response = transport.query(Name::from_ascii("probe.t.example.lab.")?, RecordType::A).await?;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(transport.current.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_asked_again_over_tcp() -> Result<()> {
+        let (_server, addr) = udp_and_tcp_server().await?;
+
+        let transport = Transport::connect(&[addr], 0).await?;
+        let response = transport.query(Name::from_ascii("data.t.example.lab.")?, RecordType::TXT).await?;
+
+        assert!(!response.truncated());
+        assert_eq!(response.answers().iter().map(Record::data).collect::<Vec<_>>(), [&RData::TXT(oversized_txt())]);
+        assert!(transport.upstreams[0].tcp.lock().await.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dot_with_matching_pin() -> Result<()> {
+        let (_server, addr, pin) = dot_server().await?;
+
+        let transport = dot_client(addr, &pin).await?;
+        let response = transport.query(Name::from_ascii("probe.t.example.lab.")?, RecordType::A).await?;
+
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().iter().map(Record::data).collect::<Vec<_>>(), [&RData::A(ANSWER)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dot_pin_takes_colons_and_uppercase() -> Result<()> {
+        let (_server, addr, pin) = dot_server().await?;
+        let pin = pin.as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8_lossy(pair).to_ascii_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let transport = dot_client(addr, &pin).await?;
+        transport.query(Name::from_ascii("probe.t.example.lab.")?, RecordType::A).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dot_with_wrong_pin_fails() -> Result<()> {
+        let (_server, addr, _) = dot_server().await?;
+
+        // The handshake may only run once the first query goes out
+        let result = match dot_client(addr, &"00".repeat(32)).await {
+            Ok(transport) => transport.query(Name::from_ascii("probe.t.example.lab.")?, RecordType::A).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        assert!(result.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use hickory_client::proto::op::ResponseCode;
+use tokio::select;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::Sender;
+use tokio::task::{AbortHandle, JoinSet};
+use shared::{
+    capacity, encode_packet, encrypt, open_frame, poll_to_bytes,
+    Action, Compression, DownstreamKind, EncodingKind, FragmentId, Inserted, Keys, Packet, Reassembler, SendWindow,
+    ACK_LEN, MAX_ATTEMPTS, SEND_WINDOW, SEQUENCE_LEN,
+};
+use crate::to_name;
+use crate::transport::Transport;
+
+/// Incomplete downstream messages are dropped after this long
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An established session, everything needed to move data both ways
+pub struct Tunnel {
+    pub client: Arc<Transport>,
+    pub sender: Sender<Action>,
+    pub session_id: u32,
+    pub keys: Keys,
+    pub encoding: EncodingKind,
+    /// Record type data queries ask for, the server answers in it with whatever it has queued
+    pub downstream: DownstreamKind,
+    pub domain: String,
+    /// Data queries kept in flight at once
+    pub window: usize,
+    /// Agreed on with the server, applies both ways
+    pub compression: Compression,
+    next_message_id: AtomicU16,
+    /// Every poll gets the next one, the server drops polls it has seen before
+    next_poll_sequence: AtomicU32,
+    reassembler: Mutex<Reassembler>,
+    /// Downstream fragments received since the last poll, which acknowledges them
+    acks: Mutex<Vec<FragmentId>>,
+}
+
+impl Tunnel {
+    pub fn new(client: Arc<Transport>, sender: Sender<Action>, session_id: u32, keys: Keys, encoding: EncodingKind, downstream: DownstreamKind, domain: String) -> Self {
+        Self {
+            client,
+            sender,
+            session_id,
+            keys,
+            encoding,
+            downstream,
+            domain,
+            window: SEND_WINDOW,
+            compression: Compression::None,
+            next_message_id: AtomicU16::new(1),
+            next_poll_sequence: AtomicU32::new(1),
+            reassembler: Mutex::new(Reassembler::new(REASSEMBLY_TIMEOUT)),
+            acks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    async fn log(&self, msg: String) {
+        self.sender.send(Action::Log(msg)).await.ok();
+    }
+
+    /// Sends a message with up to `window` data queries in flight at once, fragments the server doesn't acknowledge in time are sent again.
+    /// A fragment only goes out again once the transport has given up on the query carrying it, so no fragment has two queries in flight.
+    /// Answers are matched to their queries by DNS message id, and to their fragments by the acknowledgement they carry
+    pub async fn send_message(self: &Arc<Self>, data: &[u8]) -> Result<()> {
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        let names = encrypt(data, self.session_id, message_id, &self.keys.upstream, self.compression, self.encoding.encoding(), &self.domain)?;
+        let total = names.len();
+
+        self.log(format!("Sending {} bytes in {} DNS queries ({} bytes per query, {} in flight)...",
+                         data.len(),
+                         total,
+                         capacity(&self.domain, self.encoding.encoding()),
+                         self.window)).await;
+
+        let started = Instant::now();
+        let mut window = SendWindow::new(self.window, self.client.deadline(), MAX_ATTEMPTS);
+        for (index, name) in names.into_iter().enumerate() {
+            window.push(FragmentId::new(message_id, index as u16), name);
+        }
+
+        // Dropping the set on an early return aborts whatever is still in flight
+        let mut in_flight = JoinSet::new();
+        let mut queries = HashMap::new();
+        while !window.is_empty() {
+            while let Some((id, name)) = window.next_to_send()? {
+                // The last query for it has given up by now, or is about to and would only go on next to the new one
+                if let Some(query) = queries.remove(&id) {
+                    AbortHandle::abort(&query);
+                }
+                let tunnel = self.clone();
+                queries.insert(id, in_flight.spawn(async move {
+                    let acked = tunnel.send_fragment(id, &name).await;
+                    (id, acked)
+                }));
+            }
+
+            // Wait for an answer, or for the oldest fragment to be due again when the window is full
+            let deadline = window.next_deadline().map(tokio::time::Instant::from_std);
+            select! {
+                Some(joined) = in_flight.join_next() => {
+                    match joined {
+                        Ok((id, acked)) => {
+                            queries.remove(&id);
+                            let index = id.fragment_index as usize + 1;
+                            match acked {
+                                Ok(true) => {
+                                    window.ack(id);
+                                }
+                                Ok(false) => self.log(format!("[{}/{}] Not acknowledged, will resend", index, total)).await,
+                                Err(e) => self.log(format!("[{}/{}] Error: {}, will resend", index, total, e)).await,
+                            }
+                        }
+                        // Aborted for a resend
+                        Err(e) if e.is_cancelled() => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {}
+                else => {}
+            }
+        }
+
+        let elapsed = started.elapsed();
+        self.log(format!("All queries completed in {:.2}s ({:.0} bytes/s).",
+                         elapsed.as_secs_f64(),
+                         data.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON))).await;
+
+        Ok(())
+    }
+
+    /// Sends one fragment, returns whether the server acknowledged it. Anything the server sent down with the acknowledgement is taken in too
+    async fn send_fragment(&self, id: FragmentId, name: &str) -> Result<bool> {
+        let response = self.client.query(to_name(name)?, self.downstream.record_type()).await?;
+        if response.answers().is_empty() {
+            return Ok(false);
+        }
+
+        let payload = self.downstream.decode(response.answers().iter().map(|record| record.data()), &self.domain)?;
+        if FragmentId::from_bytes(&payload) != Some(id) {
+            return Ok(false);
+        }
+        self.receive(&payload[ACK_LEN..]).await?;
+
+        Ok(true)
+    }
+
+    /// Asks the server for whatever it has queued, acknowledging what came down since the last poll.
+    /// Acknowledgements only count as sent once the poll gets an answer, a lost poll leaves them for the next one.
+    /// Returns whether anything came down this time
+    pub async fn poll(&self) -> Result<bool> {
+        let acks = {
+            let mut acks = self.acks.lock().await;
+            let room = capacity(&self.domain, self.encoding.encoding()).saturating_sub(SEQUENCE_LEN);
+            let count = acks.len().min(room / ACK_LEN);
+            acks.drain(..count).collect::<Vec<_>>()
+        };
+        let sequence = self.next_poll_sequence.fetch_add(1, Ordering::Relaxed);
+        let poll = Packet::Poll {
+            session_id: self.session_id,
+            sealed: self.keys.upstream.seal(&poll_to_bytes(sequence, &acks), &self.session_id.to_be_bytes()),
+        };
+
+        let response = self.client.query(
+            to_name(&encode_packet(&poll, self.encoding.encoding(), &self.domain)?)?,
+            self.downstream.record_type(),
+        ).await;
+        let response = match response {
+            Ok(response) if response.response_code() == ResponseCode::NoError => response,
+            response => {
+                self.acks.lock().await.splice(..0, acks);
+                return match response {
+                    Ok(response) => Err(eyre!("Poll answered {}", response.response_code())),
+                    Err(e) => Err(e),
+                };
+            }
+        };
+
+        let frame = self.downstream.decode(response.answers().iter().map(|record| record.data()), &self.domain)?;
+        self.receive(&frame).await
+    }
+
+    /// Polls forever, backing off while the server has nothing and snapping back once data flows
+    pub async fn poll_loop(&self, interval: Duration, max_interval: Duration) {
+        let mut delay = interval;
+        loop {
+            tokio::time::sleep(delay).await;
+
+            match self.poll().await {
+                Ok(true) => delay = interval,
+                Ok(false) => delay = (delay * 2).min(max_interval),
+                Err(e) => {
+                    self.log(format!("Error polling server: {}", e)).await;
+                    delay = (delay * 2).min(max_interval);
+                }
+            }
+        }
+    }
+
+    /// Takes in a downstream frame, reporting the message once all of its frames are in.
+    /// Every frame gets acknowledged, duplicates too since the server resends when an acknowledgement goes missing.
+    /// Returns whether there was a frame at all
+    async fn receive(&self, frame: &[u8]) -> Result<bool> {
+        if frame.is_empty() {
+            return Ok(false);
+        }
+
+        let (header, fragment) = open_frame(frame, &self.keys.downstream)?;
+        self.acks.lock().await.push(FragmentId::of(&header));
+        let message = {
+            let mut reassembler = self.reassembler.lock().await;
+            reassembler.evict_expired();
+            reassembler.insert(header, fragment)
+        };
+        if let Inserted::Complete(data) = message {
+            let data = self.compression.decompress(data, header.flags)?;
+            self.sender.send(Action::Received {
+                message_id: header.message_id,
+                data,
+            }).await.ok();
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use hickory_server::ServerFuture;
+    use hickory_server::authority::MessageResponseBuilder;
+    use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+    use server::handler::{Config, MyHandler};
+    use server::session::Sessions;
+    use shared::{new_session_id, Handshake, Psk};
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc::Receiver;
+    use crate::handshake;
+    use super::*;
+
+    const DOMAIN: &str = "t.example.lab";
+    const SECRET: &str = "sisyphean";
+    /// Out of every 10 packets, how many the lossy path drops in each direction
+    const LOSS: u64 = 3;
+
+    /// Turns every query away, like the server does with polls of sessions it doesn't know
+    struct Refusing;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Refusing {
+        async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
+            let response = MessageResponseBuilder::from_message_request(request)
+                .error_msg(request.header(), ResponseCode::Refused);
+
+            response_handle.send_response(response).await.expect("response sent")
+        }
+    }
+
+    async fn refusing_server() -> Result<(ServerFuture<Refusing>, SocketAddr)> {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        let mut server = ServerFuture::new(Refusing);
+        server.register_socket(socket);
+
+        Ok((server, addr))
+    }
+
+    /// Tunnel over `transport` with made up keys, for servers that never get to open anything
+    fn tunnel(transport: Transport) -> Result<Tunnel> {
+        let (sender, _) = tokio::sync::mpsc::channel(1);
+        let handshake = Handshake::new();
+        let public = handshake.public();
+        let keys = handshake.finish(&public, &public)?;
+
+        Ok(Tunnel::new(Arc::new(transport), sender, 1, keys, EncodingKind::Base32, DownstreamKind::Txt, DOMAIN.to_string()))
+    }
+
+    #[tokio::test]
+    async fn acks_outlive_a_failed_poll() -> Result<()> {
+        let (_server, addr) = refusing_server().await?;
+        let tunnel = tunnel(Transport::connect(&[addr], 0).await?)?;
+
+        let acks = [FragmentId::new(1, 0), FragmentId::new(1, 1)];
+        tunnel.acks.lock().await.extend(acks);
+        assert!(tunnel.poll().await.is_err());
+        assert_eq!(*tunnel.acks.lock().await, acks);
+
+        // Ones that came down meanwhile go after them
+        tunnel.acks.lock().await.push(FragmentId::new(2, 0));
+        assert!(tunnel.poll().await.is_err());
+        assert_eq!(*tunnel.acks.lock().await, [acks[0], acks[1], FragmentId::new(2, 0)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fragments_go_out_again_only_once_their_query_gave_up() -> Result<()> {
+        // Never answers, only counts what comes in
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let transport = Transport::connect(&[silent.local_addr()?], 1).await?
+            .with_timeout(Duration::from_millis(50));
+        let tunnel = Arc::new(tunnel(transport)?.with_window(1));
+
+        let counter = tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let mut queries = 0;
+            while tokio::time::timeout(Duration::from_millis(200), silent.recv(&mut buf)).await.is_ok() {
+                queries += 1;
+            }
+            queries
+        });
+        assert!(tunnel.send_message(b"hello").await.is_err());
+
+        // Every attempt of the window is one query and its retry, none of them overlap
+        assert_eq!(counter.await?, MAX_ATTEMPTS as usize * 2);
+        Ok(())
+    }
+
+    /// The real server on a loopback port, along with its sessions and what it reports
+    async fn tunnel_server() -> Result<(ServerFuture<MyHandler>, SocketAddr, Arc<Mutex<Sessions>>, Receiver<Action>)> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1024);
+        let config = Config {
+            psk: Psk::from_secret(SECRET),
+            password: None,
+            encodings: EncodingKind::ALL.to_vec(),
+            domain: DOMAIN.to_string(),
+            zone: None,
+            ttl: 0,
+            reassembly_timeout: REASSEMBLY_TIMEOUT,
+            response_size: 4096,
+            compression: true,
+        };
+        let sessions = Arc::new(Mutex::new(Sessions::new()));
+        let mut server = ServerFuture::new(MyHandler::new(sender, config, sessions.clone()));
+        let listening = server::listen(&mut server, "127.0.0.1:0".parse()?, None).await?;
+
+        Ok((server, listening.addr, sessions, receiver))
+    }
+
+    /// Whole messages reported on `receiver`, either way, everything else is dropped so senders never block on it
+    fn collect_messages(mut receiver: Receiver<Action>) -> Arc<std::sync::Mutex<Vec<Vec<u8>>>> {
+        let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let collected = messages.clone();
+        tokio::spawn(async move {
+            while let Some(action) = receiver.recv().await {
+                if let Action::Message { data, .. } | Action::Received { data, .. } = action {
+                    collected.lock().unwrap().push(data);
+                }
+            }
+        });
+        messages
+    }
+
+    /// Drops `LOSS` out of every 10 packets, picked by a fixed pseudo-random sequence
+    struct Loss {
+        state: std::sync::Mutex<u64>,
+        dropped: AtomicUsize,
+    }
+
+    impl Loss {
+        fn drops(&self) -> bool {
+            let mut state = self.state.lock().unwrap();
+            // xorshift64
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            let drops = *state % 10 < LOSS;
+            if drops {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            drops
+        }
+    }
+
+    /// Relays queries to `server` and answers back, losing some of each on the way.
+    /// Every query gets a socket of its own towards the server, the way a resolver would forward it
+    async fn lossy_path(server: SocketAddr) -> Result<(SocketAddr, Arc<Loss>)> {
+        let front = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let addr = front.local_addr()?;
+        let loss = Arc::new(Loss {
+            state: std::sync::Mutex::new(0x2545_f491_4f6c_dd1d),
+            dropped: AtomicUsize::new(0),
+        });
+
+        let relay_loss = loss.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok((len, client)) = front.recv_from(&mut buf).await {
+                if relay_loss.drops() {
+                    continue;
+                }
+                let query = buf[..len].to_vec();
+                let (front, loss) = (front.clone(), relay_loss.clone());
+                tokio::spawn(async move {
+                    let back = UdpSocket::bind("127.0.0.1:0").await?;
+                    back.send_to(&query, server).await?;
+                    let mut buf = [0u8; 4096];
+                    let len = back.recv(&mut buf).await?;
+                    if !loss.drops() {
+                        front.send_to(&buf[..len], client).await?;
+                    }
+                    Ok::<_, std::io::Error>(())
+                });
+            }
+        });
+
+        Ok((addr, loss))
+    }
+
+    #[tokio::test]
+    async fn messages_arrive_whole_and_once_over_a_lossy_path() -> Result<()> {
+        let (_server, server_addr, sessions, server_events) = tunnel_server().await?;
+        let delivered = collect_messages(server_events);
+        let (lossy_addr, loss) = lossy_path(server_addr).await?;
+
+        // The key exchange goes straight to the server, it's the data that has to make it through the losses
+        let session_id = new_session_id();
+        let direct = Transport::connect(&[server_addr], 0).await?;
+        let (keys, _) = handshake(&direct, &Psk::from_secret(SECRET), session_id, Compression::None, EncodingKind::Base32, DOMAIN).await?;
+
+        let (sender, client_events) = tokio::sync::mpsc::channel(1024);
+        let received = collect_messages(client_events);
+        let transport = Transport::connect(&[lossy_addr], 3).await?
+            .with_timeout(Duration::from_millis(200));
+        let tunnel = Arc::new(Tunnel::new(Arc::new(transport), sender, session_id, keys, EncodingKind::Base32, DownstreamKind::Txt, DOMAIN.to_string()));
+
+        // Upstream, spread over a few dozen fragments
+        let upstream = (0..4000u32).map(|index| (index * 7 % 251) as u8).collect::<Vec<_>>();
+        tunnel.send_message(&upstream).await?;
+        assert_eq!(*delivered.lock().unwrap(), [upstream]);
+
+        // Downstream, over a handful of polls
+        let downstream = (0..3000u32).map(|index| (index * 13 % 241) as u8).collect::<Vec<_>>();
+        sessions.lock().await.get_mut(&session_id).expect("the session").outbox.push_back(downstream.clone());
+        for _ in 0..200 {
+            tunnel.poll().await.ok();
+            if !received.lock().unwrap().is_empty() && tunnel.acks.lock().await.is_empty() {
+                break;
+            }
+        }
+        // Frames the server sends again once more don't come out as the message a second time
+        for _ in 0..5 {
+            tunnel.poll().await.ok();
+        }
+        assert_eq!(*received.lock().unwrap(), [downstream]);
+
+        assert!(loss.dropped.load(Ordering::Relaxed) > 0);
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use shared::{Command, CommandMap, State};
+use shared::{Action, Command, CommandMap, State};
 
 fn exit(state: &mut State, _args: &[&str]) -> color_eyre::Result<()> {
     println!("Exit called!");
@@ -9,7 +9,7 @@ fn exit(state: &mut State, _args: &[&str]) -> color_eyre::Result<()> {
 }
 
 fn help(state: &mut State, _args: &[&str]) -> color_eyre::Result<()> {
-    println!("\nCommands\n========"); // commands to implement, clients (show list of clients), info (show server info), connect (init message session with a client), etc.
+    println!("\nCommands\n========"); // commands to implement, info (show server info), etc.
     let max_len = state.commands.keys()
         .map(|cmd_name| cmd_name.len())
         .reduce(|name_len, new_len| name_len.max(new_len))
@@ -17,7 +17,82 @@ fn help(state: &mut State, _args: &[&str]) -> color_eyre::Result<()> {
 
 
     for (cmd_str, cmd) in &state.commands {
-        state.printer.print(format!("{:max_len$}  -\t{}", cmd_str, cmd.description).into())?;
+        state.printer.print(format!("{:max_len$}  -\t{}", cmd_str, cmd.description))?;
+    }
+
+    Ok(())
+}
+
+fn send(state: &mut State, args: &[&str]) -> color_eyre::Result<()> {
+    let Some(session_id) = args.first().and_then(|id| u32::from_str_radix(id, 16).ok()) else {
+        state.printer.print("Usage: send <session id> <message>".to_string())?;
+        return Ok(());
+    };
+    let message = &args[1..];
+
+    state.sender.blocking_send(Action::Send {
+        session_id,
+        data: message.join(" ").into_bytes(),
+    }).ok();
+
+    Ok(())
+}
+
+fn clients(state: &mut State, _args: &[&str]) -> color_eyre::Result<()> {
+    state.sender.blocking_send(Action::ListClients).ok();
+
+    Ok(())
+}
+
+fn kick(state: &mut State, args: &[&str]) -> color_eyre::Result<()> {
+    let Some(session_id) = args.first().and_then(|id| u32::from_str_radix(id, 16).ok()) else {
+        state.printer.print("Usage: kick <session id>".to_string())?;
+        return Ok(());
+    };
+
+    state.sender.blocking_send(Action::Kick(session_id)).ok();
+    if state.active_session == Some(session_id) {
+        state.active_session = None;
+        state.sender.blocking_send(Action::Focus(None)).ok();
+    }
+
+    Ok(())
+}
+
+fn connect(state: &mut State, args: &[&str]) -> color_eyre::Result<()> {
+    let Some(session_id) = args.first().and_then(|id| u32::from_str_radix(id, 16).ok()) else {
+        state.printer.print("Usage: connect <session id>".to_string())?;
+        return Ok(());
+    };
+
+    let (exists, answer) = tokio::sync::oneshot::channel();
+    state.sender.blocking_send(Action::Lookup { session_id, exists }).ok();
+    if !answer.blocking_recv().unwrap_or(false) {
+        state.printer.print(format!("No session {:08x}", session_id))?;
+        return Ok(());
+    }
+
+    state.active_session = Some(session_id);
+    state.sender.blocking_send(Action::Focus(Some(session_id))).ok();
+    state.printer.print(format!("Chatting with session {:08x}, type back to return", session_id))?;
+
+    Ok(())
+}
+
+/// Handles a line typed while connected to a session, everything but `back` is sent to the client
+pub fn chat(state: &mut State, session_id: u32, line: &str) -> color_eyre::Result<()> {
+    match line.trim() {
+        "" => {}
+        "back" => {
+            state.active_session = None;
+            state.sender.blocking_send(Action::Focus(None)).ok();
+        }
+        _ => {
+            state.sender.blocking_send(Action::Send {
+                session_id,
+                data: line.as_bytes().to_vec(),
+            }).ok();
+        }
     }
 
     Ok(())
@@ -31,6 +106,75 @@ pub fn init_commands() -> CommandMap {
     commands.insert("help", Command::new("Shows this menu", help,
                                          // false, false, None
     ));
+    commands.insert("clients", Command::new("Lists connected clients and queries turned away", clients,
+                                         // false, false, None
+    ));
+    commands.insert("kick", Command::new("Drops a client's session (kick <session id>)", kick,
+                                         // false, false, None
+    ));
+    commands.insert("connect", Command::new("Chats with a client, lines typed are sent to it (connect <session id>)", connect,
+                                         // false, false, None
+    ));
+    commands.insert("send", Command::new("Queues a message for a client (send <session id> <message>)", send,
+                                         // false, false, None
+    ));
 
     commands
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use reedline::ExternalPrinter;
+    use tokio::sync::mpsc::Receiver;
+    use super::*;
+
+    fn state() -> (State, Receiver<Action>) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(8);
+        (State::new(init_commands(), &ExternalPrinter::new(8), sender), receiver)
+    }
+
+    /// Stands in for the event loop, which only knows session 1
+    fn event_loop(mut receiver: Receiver<Action>) -> std::thread::JoinHandle<Vec<Option<u32>>> {
+        std::thread::spawn(move || {
+            let mut focus = Vec::new();
+            while let Some(action) = receiver.blocking_recv() {
+                match action {
+                    Action::Lookup { session_id, exists } => {
+                        exists.send(session_id == 1).ok();
+                    }
+                    Action::Focus(session_id) => focus.push(session_id),
+                    _ => {}
+                }
+            }
+            focus
+        })
+    }
+
+    #[test]
+    fn connect_only_focuses_known_sessions() {
+        let (mut state, receiver) = state();
+        let event_loop = event_loop(receiver);
+
+        connect(&mut state, &["deadbeef"]).unwrap();
+        assert_eq!(state.active_session, None);
+        connect(&mut state, &["1"]).unwrap();
+        assert_eq!(state.active_session, Some(1));
+
+        drop(state);
+        assert_eq!(event_loop.join().unwrap(), [Some(1)]);
+    }
+
+    #[test]
+    fn kicking_the_focused_session_unfocuses_it() {
+        let (mut state, receiver) = state();
+        let event_loop = event_loop(receiver);
+
+        connect(&mut state, &["1"]).unwrap();
+        kick(&mut state, &["2"]).unwrap();
+        assert_eq!(state.active_session, Some(1));
+        kick(&mut state, &["1"]).unwrap();
+        assert_eq!(state.active_session, None);
+
+        drop(state);
+        assert_eq!(event_loop.join().unwrap(), [Some(1), None]);
+    }
+}
@@ -1,22 +1,562 @@
-use hickory_proto::op::{Header, ResponseCode};
-use hickory_proto::rr::rdata::A;
-use hickory_proto::rr::{RData, Record, RecordType};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use color_eyre::eyre::eyre;
+use hickory_proto::op::{Edns, Header, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_proto::xfer::Protocol;
 use hickory_server::authority::MessageResponseBuilder;
 use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+use tokio::sync::Mutex;
 use tokio::sync::mpsc::Sender;
-use shared::{Action, decrypt};
+use shared::{
+    Action, Cipher, Compression, ConnectPassword, CryptoError, DownstreamKind, EncodingKind, FragmentId, FrameHeader, Freshness, Handshake, Inserted,
+    Packet, Psk, Reassembler, ServerHello, answer_budget, canonical_name, capacity, check_probe, decode_packet, name_encoding, new_challenge, poll_from_bytes, strip_nonce, strip_zone,
+    AUTH_TAG_LEN, FRAME_HEADER_LEN, MAX_MESSAGE_LEN, SEAL_OVERHEAD, UDP_RESPONSE_SIZE,
+};
+use crate::session::{Session, Sessions};
+use crate::zone::{Lookup, Zone};
+
+/// How the handler treats queries, straight from the command line
+pub struct Config {
+    pub psk: Psk,
+    /// Sessions have to prove they know this before any of their data is accepted
+    pub password: Option<ConnectPassword>,
+    pub encodings: Vec<EncodingKind>,
+    /// Tunnel zone every query has to fall under, empty to take bare names
+    pub domain: String,
+    /// Static records answered around the tunnel zone, queries for them never reach the tunnel
+    pub zone: Option<Zone>,
+    /// TTL of tunnel answers, static records keep the ones from the zone file
+    pub ttl: u32,
+    pub reassembly_timeout: Duration,
+    /// Largest response downstream frames are sized to fill. Over UDP it's capped by the EDNS0 payload size the query advertises,
+    /// 512 bytes without EDNS0
+    pub response_size: usize,
+    /// Whether clients offering compression get it
+    pub compression: bool,
+}
+
+/// Queries that didn't get a proper answer, by what went wrong with them
+#[derive(Default)]
+pub struct Stats {
+    /// Not a single standard query, answered FORMERR or NOTIMP
+    pub malformed: AtomicU64,
+    /// Names that don't decode to a packet or don't open under the session's key, answered NXDOMAIN, or NODATA when a zone covers the tunnel
+    pub undecodable: AtomicU64,
+    pub refused: AtomicU64,
+    /// Polls and fragments dropped as replays, stale ones included
+    pub replayed: AtomicU64,
+    /// Answers that couldn't be built or sent
+    pub internal_errors: AtomicU64,
+}
+
+impl Stats {
+    fn count(&self, code: ResponseCode) {
+        let counter = match code {
+            ResponseCode::FormErr | ResponseCode::NotImp => &self.malformed,
+            ResponseCode::NXDomain => &self.undecodable,
+            ResponseCode::Refused => &self.refused,
+            _ => &self.internal_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed: {}, undecodable: {}, refused: {}, replays: {}, internal errors: {}",
+               self.malformed.load(Ordering::Relaxed),
+               self.undecodable.load(Ordering::Relaxed),
+               self.refused.load(Ordering::Relaxed),
+               self.replayed.load(Ordering::Relaxed),
+               self.internal_errors.load(Ordering::Relaxed))
+    }
+}
+
+/// Everything a query that isn't turned away gets back
+struct Answer {
+    answers: Vec<Record>,
+    /// The zone's SOA, on negative answers
+    soa: Vec<Record>,
+    additionals: Vec<Record>,
+    response_code: ResponseCode,
+}
+
+impl Answer {
+    fn records(answers: Vec<Record>, additionals: Vec<Record>) -> Self {
+        Self {
+            answers,
+            soa: vec![],
+            additionals,
+            response_code: ResponseCode::NoError,
+        }
+    }
+
+    fn negative(soa: &Record, response_code: ResponseCode) -> Self {
+        Self {
+            answers: vec![],
+            soa: vec![soa.clone()],
+            additionals: vec![],
+            response_code,
+        }
+    }
+}
 
-// TODO make connecting optionally locked behind a password
 pub struct MyHandler {
     sender: Sender<Action>,
-    xor_key: String,
+    psk: Psk,
+    password: Option<ConnectPassword>,
+    encodings: Vec<EncodingKind>,
+    domain: String,
+    zone: Option<Zone>,
+    ttl: u32,
+    response_size: usize,
+    compression: bool,
+    /// Shared with the event loop, which queues outgoing messages on them
+    sessions: Arc<Mutex<Sessions>>,
+    reassembler: Arc<Mutex<Reassembler>>,
+    stats: Arc<Stats>,
 }
 
 impl MyHandler {
-    pub fn new(sender: Sender<Action>, xor_key: String) -> Self {
+    pub fn new(sender: Sender<Action>, config: Config, sessions: Arc<Mutex<Sessions>>) -> Self {
+        // The largest message cut into the smallest fragments any accepted encoding carries
+        let max_fragments = config.encodings.iter()
+            .map(|kind| capacity(&config.domain, kind.encoding()))
+            .min()
+            .filter(|&capacity| capacity > 0)
+            .map_or(u16::MAX, |capacity| MAX_MESSAGE_LEN.div_ceil(capacity).min(u16::MAX as usize) as u16);
+
         Self {
             sender,
-            xor_key
+            psk: config.psk,
+            password: config.password,
+            encodings: config.encodings,
+            domain: config.domain,
+            zone: config.zone,
+            ttl: config.ttl,
+            response_size: config.response_size,
+            compression: config.compression,
+            sessions,
+            reassembler: Arc::new(Mutex::new(Reassembler::new(config.reassembly_timeout).with_max_fragments(max_fragments))),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    /// Shared with the event loop, which shows them along with the sessions
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Shared with the event loop, which evicts messages that stopped coming in even while no fragments arrive
+    pub fn reassembler(&self) -> Arc<Mutex<Reassembler>> {
+        self.reassembler.clone()
+    }
+
+    async fn log(&self, msg: String) {
+        self.sender.send(Action::Log(msg)).await.ok();
+    }
+
+    /// Probes and data alike are only accepted in encodings the server was started with
+    fn accepts(&self, name: &str) -> bool {
+        match check_probe(name).or_else(|| name_encoding(name)) {
+            Some(kind) => self.encodings.contains(&kind),
+            None => false,
+        }
+    }
+
+    /// Takes the offered compression as the raw byte the tag covers, only mapping it to a scheme once the tag checks out
+    async fn handshake(&self, session_id: u32, client_public: [u8; 32], compression: u8, tag: &[u8], src: SocketAddr) -> Result<ServerHello, CryptoError> {
+        self.psk.verify_client_hello(session_id, &client_public, compression, tag)?;
+
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.get(&session_id)
+            && session.client_public == client_public {
+            return Ok(session.server_hello.clone());
+        }
+
+        let handshake = Handshake::new();
+        let server_public = handshake.public();
+        let keys = handshake.finish(&client_public, &server_public)?;
+        let challenge = self.password.as_ref().map(|_| new_challenge());
+        let compression = if self.compression { Compression::from_byte(compression) } else { Compression::None };
+        let mut server_hello = ServerHello {
+            public: server_public,
+            tag: [0u8; AUTH_TAG_LEN],
+            challenge,
+            compression,
+        };
+        server_hello.tag = self.psk.server_hello_tag(session_id, &client_public, &server_public, &server_hello.tagged_bytes());
+
+        sessions.insert(session_id, Session::new(client_public, server_hello.clone(), keys, challenge, compression, src));
+
+        Ok(server_hello)
+    }
+
+    /// Checks a session's answer to its password challenge, marking it authenticated on success.
+    /// A wrong answer ends the session, so every guess costs a fresh handshake
+    async fn authenticate(&self, session_id: u32, sealed: &[u8]) -> color_eyre::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(&session_id)
+            .ok_or_else(|| eyre!("unknown session {:08x}", session_id))?;
+        let (Some(password), Some(challenge)) = (&self.password, session.challenge) else {
+            return Ok(());
+        };
+
+        let response = session.keys.upstream.open(sealed, &session_id.to_be_bytes())?;
+        if let Err(e) = password.verify(session_id, &challenge, &response) {
+            sessions.remove(&session_id);
+            return Err(e.into());
+        }
+        session.challenge = None;
+
+        Ok(())
+    }
+
+    /// Files a fragment away and reports the message once it's whole and decompressed, dropping any that took too long.
+    /// Fragments the reassembler has already are duplicates, counted as replays and not taken as data the session sent
+    async fn reassemble(&self, header: FrameHeader, fragment: Vec<u8>) -> Freshness {
+        let len = fragment.len() as u64;
+        let (inserted, evicted) = {
+            let mut reassembler = self.reassembler.lock().await;
+            (reassembler.insert(header, fragment), reassembler.evict_expired())
+        };
+
+        for (session_id, message_id) in evicted {
+            self.log(format!("Dropped incomplete message {} from session {:08x}", message_id, session_id)).await;
+        }
+        let message = match inserted {
+            Inserted::Pending => None,
+            Inserted::Complete(data) => Some(data),
+            Inserted::Duplicate => {
+                if let Some(session) = self.sessions.lock().await.get_mut(&header.session_id) {
+                    session.replays += 1;
+                }
+                return Freshness::Duplicate;
+            }
+            Inserted::Mismatch => {
+                self.log(format!("Dropped message {} from session {:08x}: fragment {} doesn't fit the fragments before it",
+                                 header.message_id, header.session_id, header.fragment_index)).await;
+                return Freshness::New;
+            }
+            Inserted::Refused => {
+                self.log(format!("Dropped fragment {} of message {} from session {:08x}: too many fragments or messages coming in",
+                                 header.fragment_index, header.message_id, header.session_id)).await;
+                return Freshness::New;
+            }
+        };
+
+        let compression = match self.sessions.lock().await.get_mut(&header.session_id) {
+            Some(session) => {
+                session.bytes_in += len;
+                if message.is_some() {
+                    session.messages += 1;
+                    session.delivered(header.message_id);
+                }
+                session.compression
+            }
+            None => return Freshness::New,
+        };
+        let Some(data) = message else {
+            return Freshness::New;
+        };
+        let data = match compression.decompress(data, header.flags) {
+            Ok(data) => data,
+            Err(e) => {
+                self.log(format!("Error: Could not decompress message {} from session {:08x}: {}", header.message_id, header.session_id, e)).await;
+                return Freshness::New;
+            }
+        };
+        self.sender.send(Action::Message {
+            session_id: header.session_id,
+            message_id: header.message_id,
+            data,
+        }).await.ok();
+
+        Freshness::New
+    }
+
+    /// Key to open what a session sent, as long as it exists and has proven the password
+    async fn upstream_cipher(&self, session_id: u32, src: SocketAddr, what: &str) -> Result<Cipher, ResponseCode> {
+        let Some((cipher, authenticated)) = self.sessions.lock().await
+            .get_mut(&session_id)
+            .map(|session| {
+                session.touch(src);
+                (session.keys.upstream.clone(), session.is_authenticated())
+            }) else {
+            self.log(format!("Rejected {}: unknown session {:08x}", what, session_id)).await;
+            return Err(ResponseCode::Refused);
+        };
+        if !authenticated {
+            self.log(format!("Rejected {}: session {:08x} has not proven the password", what, session_id)).await;
+            return Err(ResponseCode::Refused);
+        }
+
+        Ok(cipher)
+    }
+
+    /// Answers a query with the session's next downstream frame, spread over records of the queried type.
+    /// Answers to data queries lead with the acknowledgement of the fragment they carried
+    async fn downstream(&self, session_id: u32, name: &str, kind: DownstreamKind, ack: Option<FragmentId>, response_size: usize) -> Result<Vec<RData>, ResponseCode> {
+        let frame = {
+            let mut sessions = self.sessions.lock().await;
+            // Kicked while the query was on its way
+            let session = sessions.get_mut(&session_id).ok_or(ResponseCode::Refused)?;
+            let frame = session.next_frame(session_id, name, kind, &self.domain, response_size);
+            if let Ok(frame) = &frame {
+                session.bytes_out += frame.len().saturating_sub(FRAME_HEADER_LEN + SEAL_OVERHEAD) as u64;
+            }
+            frame
+        };
+
+        let payload = frame.map(|frame| match ack {
+            Some(ack) => [&ack.to_bytes()[..], &frame].concat(),
+            None => frame,
+        });
+        match payload.and_then(|payload| kind.encode(&payload, &self.domain, answer_budget(response_size))) {
+            Ok(rdatas) => Ok(rdatas),
+            Err(e) => {
+                self.log(format!("Error: Could not answer session {:08x}: {}", session_id, e)).await;
+                Err(ResponseCode::ServFail)
+            }
+        }
+    }
+
+    /// Works out what to answer a query with, or the response code to turn it away with. Downstream data is packed to fill `response_size` bytes
+    async fn answer(&self, name: &str, query_type: RecordType, src: SocketAddr, response_size: usize) -> Result<Vec<RData>, ResponseCode> {
+        let ack = RData::A(A::new(4, 20, 69, 67));
+
+        let Some(name) = strip_zone(name, &self.domain) else {
+            self.log(format!("Refused query outside the tunnel zone: {}", name)).await;
+            return Err(ResponseCode::Refused);
+        };
+        // Everything past here, the answer caches included, goes by the name without it
+        let Some(name) = strip_nonce(name) else {
+            return Err(ResponseCode::NXDomain);
+        };
+        // Resolvers randomize the case of names (0x20), a retransmit has to come out as the same name all the same
+        let name = canonical_name(name);
+        let name = name.as_ref();
+
+        if !self.accepts(name) {
+            return Err(ResponseCode::Refused);
+        }
+
+        if let Some(kind) = check_probe(name) {
+            self.log(format!("Encoding probe for {} accepted", kind)).await;
+            return match query_type {
+                RecordType::A => Ok(vec![ack]),
+                _ => Err(ResponseCode::Refused),
+            };
+        }
+
+        let packet = match decode_packet(name) {
+            Ok(packet) => packet,
+            Err(e) => {
+                self.log(format!("Error: Could not decode packet: {}", e)).await;
+                return Err(ResponseCode::NXDomain);
+            }
+        };
+
+        match (packet, query_type) {
+            (Packet::Hello { session_id, public, tag, compression }, RecordType::AAAA) => {
+                match self.handshake(session_id, public, compression, &tag, src).await {
+                    Ok(server_hello) => {
+                        self.log(format!("Session {:08x} established", session_id)).await;
+                        Ok(server_hello.to_records()
+                            .into_iter()
+                            .map(|octets| RData::AAAA(AAAA::from(Ipv6Addr::from(octets))))
+                            .collect())
+                    }
+                    Err(e) => {
+                        self.log(format!("Rejected handshake for session {:08x}: {}", session_id, e)).await;
+                        Err(ResponseCode::Refused)
+                    }
+                }
+            }
+            (Packet::Auth { session_id, sealed }, RecordType::A) => {
+                match self.authenticate(session_id, &sealed).await {
+                    Ok(()) => {
+                        self.log(format!("Session {:08x} authenticated", session_id)).await;
+                        Ok(vec![ack])
+                    }
+                    Err(e) => {
+                        self.log(format!("Rejected password for session {:08x}: {}", session_id, e)).await;
+                        Err(ResponseCode::Refused)
+                    }
+                }
+            }
+            (Packet::Poll { session_id, sealed }, query_type) => {
+                let kind = DownstreamKind::from_record_type(query_type).ok_or(ResponseCode::Refused)?;
+                let cipher = self.upstream_cipher(session_id, src, "poll").await?;
+                let poll = match cipher.open(&sealed, &session_id.to_be_bytes()) {
+                    Ok(poll) => poll,
+                    Err(e) => {
+                        self.log(format!("Rejected poll for session {:08x}: {}", session_id, e)).await;
+                        return Err(ResponseCode::NXDomain);
+                    }
+                };
+                let Some((sequence, acks)) = poll_from_bytes(&poll) else {
+                    self.log(format!("Rejected poll for session {:08x}: no sequence number", session_id)).await;
+                    return Err(ResponseCode::NXDomain);
+                };
+
+                let replayed = match self.sessions.lock().await.get_mut(&session_id) {
+                    // An exact retransmit gets the answer it got the first time
+                    Some(session) if session.answered(name) => false,
+                    Some(session) => {
+                        let fresh = session.accept_poll(sequence);
+                        if fresh {
+                            session.ack(&acks);
+                        }
+                        !fresh
+                    }
+                    None => return Err(ResponseCode::Refused),
+                };
+                if replayed {
+                    self.stats.replayed.fetch_add(1, Ordering::Relaxed);
+                    self.log(format!("Dropped replayed poll {} from session {:08x}", sequence, session_id)).await;
+                    return Err(ResponseCode::Refused);
+                }
+
+                self.downstream(session_id, name, kind, None, response_size).await
+            }
+            // Plain A queries only get the fragment acknowledged, any downstream record type gets data back too
+            (Packet::Data { header, sealed }, query_type)
+                if query_type == RecordType::A || DownstreamKind::from_record_type(query_type).is_some() => {
+                let session_id = header.session_id;
+                let cipher = self.upstream_cipher(session_id, src, "message").await?;
+
+                let plaintext = match cipher.open(&sealed, &header.to_bytes()) {
+                    Ok(plaintext) => plaintext,
+                    Err(e) => {
+                        self.log(format!("Rejected message for session {:08x}: {}", session_id, e)).await;
+                        return Err(ResponseCode::NXDomain);
+                    }
+                };
+
+                let freshness = match self.sessions.lock().await.get_mut(&session_id) {
+                    // An exact retransmit gets the answer it got the first time, its fragment is in already
+                    Some(session) if session.answered(name) => None,
+                    Some(session) => Some(session.message_freshness(header.message_id)),
+                    None => return Err(ResponseCode::Refused),
+                };
+                // Fragments of messages still coming in can be replayed too, once their answer is out of the cache
+                let freshness = match freshness {
+                    Some(Freshness::New) => Some(self.reassemble(header, plaintext).await),
+                    freshness => freshness,
+                };
+                match freshness {
+                    Some(Freshness::New) | None => {}
+                    Some(freshness) => {
+                        self.stats.replayed.fetch_add(1, Ordering::Relaxed);
+                        self.log(format!("Dropped {} fragment {} of message {} from session {:08x}",
+                                         if freshness == Freshness::Stale { "stale" } else { "replayed" },
+                                         header.fragment_index,
+                                         header.message_id,
+                                         session_id)).await;
+                    }
+                }
+
+                // Retransmitted fragments are acknowledged again, the first acknowledgement may have been lost.
+                // Ones of messages delivered already get nothing more than that, and stale ones not even that
+                let fragment = FragmentId::of(&header);
+                match (freshness, DownstreamKind::from_record_type(query_type)) {
+                    (Some(Freshness::Stale), _) => Err(ResponseCode::Refused),
+                    (Some(Freshness::Duplicate), Some(kind)) => kind.encode(&fragment.to_bytes(), &self.domain, answer_budget(response_size))
+                        .map_err(|_| ResponseCode::ServFail),
+                    (_, Some(kind)) => self.downstream(session_id, name, kind, Some(fragment), response_size).await,
+                    (freshness, None) => {
+                        if freshness == Some(Freshness::New)
+                            && let Some(session) = self.sessions.lock().await.get_mut(&session_id) {
+                            session.remember(name, Vec::new());
+                        }
+                        Ok(vec![RData::A(A::from(Ipv4Addr::from(fragment.to_bytes())))])
+                    }
+                }
+            }
+            _ => Err(ResponseCode::Refused),
+        }
+    }
+
+    /// What to send back for a request, only standard queries asking a single question get anything.
+    /// Names the zone has records for, or that fall in the zone but outside the tunnel, are answered from the zone
+    async fn records(&self, request: &Request, response_size: usize) -> Result<Answer, ResponseCode> {
+        let header = request.header();
+        if header.message_type() != MessageType::Query {
+            return Err(ResponseCode::FormErr);
+        }
+        if header.op_code() != OpCode::Query {
+            return Err(ResponseCode::NotImp);
+        }
+        let queries = request.queries();
+        if queries.len() != 1 {
+            return Err(ResponseCode::FormErr);
+        }
+
+        let message = queries[0].original();
+        let message_name = message.name();
+        // Labels are joined by hand since Display would escape characters some encodings use
+        let name = message_name.iter()
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(".");
+
+        if let Some(zone) = &self.zone {
+            let lower_name = queries[0].name();
+            // The tunnel apex has every tunnel name below it, so it exists even without records of its own
+            if zone.contains(lower_name) && !zone.has_records(lower_name) && self.is_tunnel_apex(&name) {
+                return Ok(Answer::negative(zone.soa(), ResponseCode::NoError));
+            }
+            if zone.contains(lower_name) && (zone.has_records(lower_name) || strip_zone(&name, &self.domain).is_none()) {
+                return Ok(Self::static_answer(zone, lower_name, message_name, message.query_type));
+            }
+        }
+
+        let rdatas = self.answer(&name, message.query_type, request.src(), response_size).await;
+        // Tunnel names the zone covers get their negative answers from it too, with the SOA resolvers cache them by.
+        // Those that don't decode are NODATA rather than NXDOMAIN, which resolvers would take to mean nothing below them exists either
+        if let Some(zone) = self.zone.as_ref().filter(|zone| zone.contains(queries[0].name())) {
+            match &rdatas {
+                Err(ResponseCode::NXDomain) => {
+                    self.stats.count(ResponseCode::NXDomain);
+                    return Ok(Answer::negative(zone.soa(), ResponseCode::NoError));
+                }
+                Ok(rdatas) if rdatas.is_empty() => return Ok(Answer::negative(zone.soa(), ResponseCode::NoError)),
+                _ => {}
+            }
+        }
+
+        let answers = rdatas?
+            .into_iter()
+            .map(|rdata| Record::from_rdata(message_name.clone(), self.ttl, rdata))
+            .collect();
+
+        Ok(Answer::records(answers, vec![]))
+    }
+
+    /// Whether the name is the tunnel zone itself, in whatever case
+    fn is_tunnel_apex(&self, name: &str) -> bool {
+        let domain = self.domain.trim_matches('.');
+        !domain.is_empty() && name.trim_end_matches('.').eq_ignore_ascii_case(domain)
+    }
+
+    /// Answers out of the zone, records owned by the query name carry it in the exact case it was asked in,
+    /// which resolvers randomizing it (0x20) check for
+    fn static_answer(zone: &Zone, name: &LowerName, query_name: &Name, query_type: RecordType) -> Answer {
+        match zone.lookup(name, query_type) {
+            Lookup::Found { mut answers, additionals } => {
+                for answer in answers.iter_mut().filter(|answer| LowerName::new(answer.name()) == *name) {
+                    answer.set_name(query_name.clone());
+                }
+                Answer::records(answers, additionals)
+            }
+            Lookup::NoData => Answer::negative(zone.soa(), ResponseCode::NoError),
+            Lookup::NxDomain => Answer::negative(zone.soa(), ResponseCode::NXDomain),
         }
     }
 }
@@ -24,44 +564,526 @@ impl MyHandler {
 #[async_trait::async_trait]
 impl RequestHandler for MyHandler {
     async fn handle_request<R: ResponseHandler>(&self, request: &Request, mut response_handle: R) -> ResponseInfo {
-        let message = request.queries()[0].original();
-        let message_name = message.name().clone();
-
-        self.sender.send(
-            Action::Log(
-                format!("Decrypting message: {}",
-                        String::from_utf8_lossy(
-                            &decrypt(vec![message_name.to_string().to_uppercase()], self.xor_key.as_bytes())
-                            .unwrap_or("Error: Could not decrypt".into())
-                        )
-                ))).await.ok();
-        
-        let builder = MessageResponseBuilder::from_message_request(request);
-
-        if let RecordType::A = message.query_type {
-            let answer_a = A::new(4, 20, 69, 67);
-            let answer = Record::from_rdata(
-                message_name,
-                60,
-                RData::A(answer_a)
-            );
-
-            let response_msg = builder.build(Header::response_from_request(request.header()),
-                                             vec![&answer],
-                                             vec![],
-                                             vec![],
-                                             vec![]);
-
-            response_handle.send_response(response_msg).await.unwrap()
-        } else {
-            let err_msg = builder.error_msg(
-                &Header::response_from_request(request.header()),
-                ResponseCode::Refused);
-
-            response_handle.send_response(err_msg).await.unwrap()
+        // Streams have no size limit short of the 64k message, UDP goes as far as the client's EDNS0 payload size
+        let response_size = match request.protocol() {
+            Protocol::Udp => self.response_size.min(request.max_payload() as usize),
+            _ => self.response_size,
+        };
+        // Only queries that came with EDNS0 get it back, advertising the size the answer was packed to
+        let edns = request.edns().map(|_| {
+            let mut edns = Edns::new();
+            edns.set_max_payload(response_size.clamp(UDP_RESPONSE_SIZE, u16::MAX as usize) as u16);
+            edns
+        });
+
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = edns {
+            builder.edns(edns);
+        }
+
+        let sent = match self.records(request, response_size).await {
+            Ok(answer) => {
+                // Everything answered comes from the tunnel or the zone, both of which this server is the authority for
+                let mut header = Header::response_from_request(request.header());
+                header.set_authoritative(true);
+                header.set_response_code(answer.response_code);
+                let response_msg = builder.build(header,
+                                                 answer.answers.iter(),
+                                                 vec![],
+                                                 answer.soa.iter(),
+                                                 answer.additionals.iter());
+
+                response_handle.send_response(response_msg).await
+            }
+            Err(code) => {
+                self.stats.count(code);
+                response_handle.send_response(builder.error_msg(request.header(), code)).await
+            }
+        };
+
+        match sent {
+            Ok(info) => info,
+            Err(e) => {
+                self.stats.internal_errors.fetch_add(1, Ordering::Relaxed);
+                self.log(format!("Error: Could not send response to {}: {}", request.src(), e)).await;
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::ServFail);
+                header.into()
+            }
         }
     }
 }
 
-// If client has not been registered, merely echo the domain name back in a TXT field (nvm this is for after we get base32 working)
-// Just send queries in base32 [data].domain.com, worry about encryption later
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::OnceLock;
+    use std::time::Instant;
+    use bytes::Bytes;
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::serialize::binary::{BinDecodable, BinEncoder};
+    use hickory_server::authority::{MessageRequest, MessageResponse};
+    use shared::{Keys, downstream_capacity, encode_packet, new_nonce_label, poll_to_bytes, probe_name, randomize_case, seal_frames, ACK_LEN};
+    use crate::session::ANSWER_CACHE_LEN;
+    use super::*;
+
+    const DOMAIN: &str = "t.example.lab";
+    const SECRET: &str = "sisyphean";
+
+    /// Keeps the response the handler sends, as it would come off the wire
+    #[derive(Clone, Default)]
+    struct Capture(Arc<std::sync::Mutex<Option<Message>>>);
+
+    #[async_trait::async_trait]
+    impl ResponseHandler for Capture {
+        async fn send_response<'a>(
+            &mut self,
+            response: MessageResponse<
+                '_,
+                'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+            >,
+        ) -> io::Result<ResponseInfo> {
+            let mut bytes = Vec::new();
+            let info = response.destructive_emit(&mut BinEncoder::new(&mut bytes)).map_err(io::Error::other)?;
+            *self.0.lock().unwrap() = Some(Message::from_vec(&bytes).map_err(io::Error::other)?);
+            Ok(info)
+        }
+    }
+
+    /// Stretching the secret is slow, tests doing many handshakes share the one key
+    fn psk() -> Psk {
+        static PSK: OnceLock<Psk> = OnceLock::new();
+        PSK.get_or_init(|| Psk::from_secret(SECRET)).clone()
+    }
+
+    fn handler(zone: Option<Zone>) -> MyHandler {
+        // Nothing listens, log lines are dropped
+        let (sender, _) = tokio::sync::mpsc::channel(1);
+        let config = Config {
+            psk: psk(),
+            password: None,
+            encodings: EncodingKind::ALL.to_vec(),
+            domain: DOMAIN.to_string(),
+            zone,
+            ttl: 0,
+            reassembly_timeout: Duration::from_secs(30),
+            response_size: 4096,
+            compression: true,
+        };
+        MyHandler::new(sender, config, Arc::new(Mutex::new(Sessions::new())))
+    }
+
+    /// Zone around the tunnel, written out and loaded the way --zone would
+    fn zone() -> Zone {
+        let path = std::env::temp_dir().join(format!("handler-test-{}-{}.zone", std::process::id(), new_nonce_label()));
+        std::fs::write(&path, "\
+$ORIGIN example.lab.
+$TTL 3600
+@       IN SOA ns1 hostmaster 1 7200 3600 1209600 300
+@       IN NS  ns1
+ns1     IN A   192.0.2.53
+www 300 IN A   192.0.2.80
+").unwrap();
+        let zone = Zone::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        zone
+    }
+
+    fn query(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::query();
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        message
+    }
+
+    /// Runs a message through the handler the way the server would, handing back what it answered
+    async fn resolve(handler: &MyHandler, message: &Message) -> Message {
+        let raw = message.to_vec().unwrap();
+        let request = Request::new(MessageRequest::from_bytes(&raw).unwrap(), Bytes::from(raw), "192.0.2.1:40000".parse().unwrap(), Protocol::Udp);
+        let capture = Capture::default();
+        handler.handle_request(&request, capture.clone()).await;
+
+        capture.0.lock().unwrap().take().expect("a response")
+    }
+
+    /// Name a packet travels under, behind a fresh nonce label like the client sends it
+    fn packet_name(packet: &Packet, kind: EncodingKind) -> String {
+        format!("{}.{}", new_nonce_label(), encode_packet(packet, kind.encoding(), DOMAIN).unwrap())
+    }
+
+    /// Goes through the key exchange like a client would, handing back the session's keys
+    async fn handshake(handler: &MyHandler, session_id: u32) -> Keys {
+        let psk = psk();
+        let client = Handshake::new();
+        let public = client.public();
+        let hello = Packet::Hello {
+            session_id,
+            public,
+            tag: psk.client_hello_tag(session_id, &public, Compression::None.to_byte()),
+            compression: Compression::None.to_byte(),
+        };
+
+        let response = resolve(handler, &query(&packet_name(&hello, EncodingKind::Base32), RecordType::AAAA)).await;
+        let records = response.answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                RData::AAAA(aaaa) => Some(aaaa.0.octets()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let server_hello = ServerHello::from_records(&records).unwrap();
+        psk.verify_server_hello(session_id, &public, &server_hello.public, &server_hello.tagged_bytes(), &server_hello.tag).unwrap();
+
+        client.finish(&public, &server_hello.public).unwrap()
+    }
+
+    /// Stand-in for a caching resolver between client and server, answers are served from its cache until their TTL runs out
+    struct CachingForwarder<'a> {
+        handler: &'a MyHandler,
+        cache: HashMap<(LowerName, RecordType), (Message, Instant)>,
+        /// Names of the queries that made it through to the handler
+        forwarded: Vec<String>,
+    }
+
+    impl<'a> CachingForwarder<'a> {
+        fn new(handler: &'a MyHandler) -> Self {
+            Self {
+                handler,
+                cache: HashMap::new(),
+                forwarded: Vec::new(),
+            }
+        }
+
+        async fn resolve(&mut self, name: &str, query_type: RecordType) -> Message {
+            let key = (LowerName::new(&Name::from_ascii(name).unwrap()), query_type);
+            if let Some((response, expires)) = self.cache.get(&key)
+                && Instant::now() < *expires {
+                return response.clone();
+            }
+
+            let response = resolve(self.handler, &query(name, query_type)).await;
+            self.forwarded.push(name.to_string());
+            if let Some(ttl) = response.answers().iter().map(Record::ttl).min()
+                && ttl > 0 {
+                self.cache.insert(key, (response.clone(), Instant::now() + Duration::from_secs(ttl as u64)));
+            }
+
+            response
+        }
+    }
+
+    fn count(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn no_question_is_formerr() {
+        let handler = handler(None);
+        let response = resolve(&handler, &Message::query()).await;
+
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(count(&handler.stats.malformed), 1);
+    }
+
+    #[tokio::test]
+    async fn several_questions_are_formerr() {
+        let handler = handler(None);
+        let mut message = query(&format!("a.{DOMAIN}"), RecordType::A);
+        message.add_query(Query::query(Name::from_ascii(format!("b.{DOMAIN}")).unwrap(), RecordType::A));
+        let response = resolve(&handler, &message).await;
+
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(count(&handler.stats.malformed), 1);
+    }
+
+    #[tokio::test]
+    async fn other_opcodes_are_notimp() {
+        let handler = handler(None);
+        let mut message = query(&format!("a.{DOMAIN}"), RecordType::A);
+        message.set_op_code(OpCode::Status);
+        let response = resolve(&handler, &message).await;
+
+        assert_eq!(response.response_code(), ResponseCode::NotImp);
+        assert_eq!(count(&handler.stats.malformed), 1);
+    }
+
+    #[tokio::test]
+    async fn responses_are_formerr() {
+        let handler = handler(None);
+        let mut message = Message::new(7, MessageType::Response, OpCode::Query);
+        message.add_query(Query::query(Name::from_ascii(format!("a.{DOMAIN}")).unwrap(), RecordType::A));
+        let response = resolve(&handler, &message).await;
+
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(count(&handler.stats.malformed), 1);
+    }
+
+    #[tokio::test]
+    async fn names_outside_the_zone_are_refused() {
+        let handler = handler(None);
+        let response = resolve(&handler, &query("www.example.com.", RecordType::A)).await;
+
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert_eq!(count(&handler.stats.refused), 1);
+    }
+
+    #[tokio::test]
+    async fn names_that_dont_decode_are_nxdomain() {
+        let handler = handler(None);
+        // Tagged base32, but five bytes aren't any packet
+        let name = format!("{}.{}.aaaaaaaa.{DOMAIN}", new_nonce_label(), EncodingKind::Base32.tag());
+        let response = resolve(&handler, &query(&name, RecordType::A)).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+        // Without the nonce label
+        let name = format!("{}.aaaaaaaa.{DOMAIN}", EncodingKind::Base32.tag());
+        let response = resolve(&handler, &query(&name, RecordType::A)).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+        assert_eq!(count(&handler.stats.undecodable), 2);
+        assert_eq!(count(&handler.stats.malformed), 0);
+    }
+
+    #[tokio::test]
+    async fn names_that_dont_open_are_nxdomain() {
+        let handler = handler(None);
+        handshake(&handler, 1).await;
+
+        let poll = Packet::Poll {
+            session_id: 1,
+            sealed: vec![0; SEAL_OVERHEAD + 4],
+        };
+        let response = resolve(&handler, &query(&packet_name(&poll, EncodingKind::Base32), RecordType::TXT)).await;
+
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(count(&handler.stats.undecodable), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_refused() {
+        let handler = handler(None);
+        let poll = Packet::Poll {
+            session_id: 1,
+            sealed: vec![0; SEAL_OVERHEAD + 4],
+        };
+        let response = resolve(&handler, &query(&packet_name(&poll, EncodingKind::Base32), RecordType::TXT)).await;
+
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert_eq!(count(&handler.stats.refused), 1);
+    }
+
+    #[tokio::test]
+    async fn in_zone_tunnel_names_that_dont_decode_are_nodata() {
+        let handler = handler(Some(zone()));
+        let name = format!("{}.{}.aaaaaaaa.{DOMAIN}", new_nonce_label(), EncodingKind::Base32.tag());
+        let response = resolve(&handler, &query(&name, RecordType::A)).await;
+
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert!(response.authoritative());
+        assert_eq!(response.name_servers().iter().map(Record::record_type).collect::<Vec<_>>(), [RecordType::SOA]);
+        assert_eq!(count(&handler.stats.undecodable), 1);
+    }
+
+    #[tokio::test]
+    async fn tunnel_apex_and_names_above_the_data_are_nodata() {
+        let handler = handler(Some(zone()));
+        // The apex, and an empty non-terminal: the encoding tag has data names below it but no nonce label
+        for name in [format!("{DOMAIN}."), "T.Example.LAB.".to_string(), format!("{}.{DOMAIN}.", EncodingKind::Base32.tag())] {
+            for query_type in [RecordType::A, RecordType::TXT] {
+                let response = resolve(&handler, &query(&name, query_type)).await;
+                assert_eq!(response.response_code(), ResponseCode::NoError, "{name} {query_type}");
+                assert!(response.authoritative());
+                assert!(response.answers().is_empty());
+                assert_eq!(response.name_servers().iter().map(Record::record_type).collect::<Vec<_>>(), [RecordType::SOA]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn static_names_are_answered_from_the_zone() {
+        let handler = handler(Some(zone()));
+
+        let response = resolve(&handler, &query("www.example.lab.", RecordType::A)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(response.answers()[0].data(), &RData::A(A::new(192, 0, 2, 80)));
+
+        // The owner comes back in the case it was asked in, resolvers doing 0x20 drop the answer otherwise
+        let response = resolve(&handler, &query("wWw.ExAmPlE.lAb.", RecordType::A)).await;
+        assert_eq!(response.answers()[0].name().to_ascii(), "wWw.ExAmPlE.lAb.");
+
+        let response = resolve(&handler, &query("www.example.lab.", RecordType::MX)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers().iter().map(Record::record_type).collect::<Vec<_>>(), [RecordType::SOA]);
+
+        let response = resolve(&handler, &query("nothing.example.lab.", RecordType::A)).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(response.name_servers().iter().map(Record::record_type).collect::<Vec<_>>(), [RecordType::SOA]);
+    }
+
+    #[tokio::test]
+    async fn fragments_replayed_past_the_answer_cache_are_dropped() {
+        let handler = handler(None);
+        let keys = handshake(&handler, 1).await;
+        let fragments = seal_frames(b"twelve bytes", 1, 1, &keys.upstream, Compression::None, 8)
+            .unwrap()
+            .into_iter()
+            .map(|(header, sealed)| packet_name(&Packet::Data { header, sealed }, EncodingKind::Base32))
+            .collect::<Vec<_>>();
+        assert_eq!(fragments.len(), 2);
+
+        let response = resolve(&handler, &query(&fragments[0], RecordType::TXT)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        {
+            let mut sessions = handler.sessions.lock().await;
+            let session = sessions.get_mut(&1).unwrap();
+            assert_eq!(session.bytes_in, 8);
+            // Something for downstream to hand out, which a replay mustn't get
+            session.outbox.push_back(b"queued".to_vec());
+            // Other queries push the fragment's answer out of the cache
+            for index in 0..ANSWER_CACHE_LEN {
+                session.remember(&format!("other{index}"), Vec::new());
+            }
+        }
+
+        let response = resolve(&handler, &query(&fragments[0], RecordType::TXT)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(count(&handler.stats.replayed), 1);
+        {
+            let sessions = handler.sessions.lock().await;
+            let session = &sessions[&1];
+            assert_eq!(session.replays, 1);
+            assert_eq!(session.bytes_in, 8);
+            assert_eq!(session.outbox.len(), 1);
+        }
+
+        // The message still completes
+        resolve(&handler, &query(&fragments[1], RecordType::A)).await;
+        let sessions = handler.sessions.lock().await;
+        assert_eq!(sessions[&1].messages, 1);
+        assert_eq!(sessions[&1].bytes_in, 12);
+    }
+
+    #[tokio::test]
+    async fn nonce_labels_get_past_a_caching_resolver() {
+        let mut handler = handler(Some(zone()));
+        handler.ttl = 5;
+        let mut forwarder = CachingForwarder::new(&handler);
+
+        // The same query twice, the way the client sends it: under a fresh nonce label each time
+        let probe = probe_name(EncodingKind::Base32, DOMAIN);
+        for _ in 0..2 {
+            let response = forwarder.resolve(&format!("{}.{probe}", new_nonce_label()), RecordType::A).await;
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert!(!response.answers().is_empty());
+            assert!(response.answers().iter().all(|record| record.ttl() == 5));
+        }
+        assert_eq!(forwarder.forwarded.len(), 2);
+        assert_ne!(forwarder.forwarded[0], forwarder.forwarded[1]);
+        assert!(forwarder.forwarded.iter().all(|name| name.ends_with(&probe)));
+
+        // Zone records keep the TTLs from the zone file, and get cached like any others
+        for _ in 0..2 {
+            let response = forwarder.resolve("www.example.lab.", RecordType::A).await;
+            assert_eq!(response.answers().iter().map(Record::ttl).collect::<Vec<_>>(), [300]);
+        }
+        let response = forwarder.resolve("ns1.example.lab.", RecordType::A).await;
+        assert_eq!(response.answers().iter().map(Record::ttl).collect::<Vec<_>>(), [3600]);
+        assert_eq!(forwarder.forwarded.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn tunnel_answers_default_to_ttl_zero() {
+        let handler = handler(Some(zone()));
+        let name = format!("{}.{}", new_nonce_label(), probe_name(EncodingKind::Base32, DOMAIN));
+        let response = resolve(&handler, &query(&name, RecordType::A)).await;
+
+        assert!(!response.answers().is_empty());
+        assert!(response.answers().iter().all(|record| record.ttl() == 0));
+    }
+
+    #[tokio::test]
+    async fn names_without_a_hex_nonce_are_nxdomain() {
+        let handler = handler(None);
+        let name = format!("nothex00.{}", probe_name(EncodingKind::Base32, DOMAIN));
+        let response = resolve(&handler, &query(&name, RecordType::A)).await;
+
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn tunnel_names_survive_case_randomization() {
+        let handler = handler(None);
+        for kind in EncodingKind::ALL.into_iter().filter(|kind| !kind.encoding().case_sensitive()) {
+            let name = randomize_case(&format!("{}.{}", new_nonce_label(), probe_name(kind, DOMAIN)));
+            let response = resolve(&handler, &query(&name, RecordType::A)).await;
+            assert_eq!(response.response_code(), ResponseCode::NoError, "{kind}: {name}");
+            assert_eq!(response.answers()[0].name().to_ascii(), name + ".");
+        }
+
+        let psk = psk();
+        let public = Handshake::new().public();
+        let hello = Packet::Hello {
+            session_id: 1,
+            public,
+            tag: psk.client_hello_tag(1, &public, Compression::None.to_byte()),
+            compression: Compression::None.to_byte(),
+        };
+        let name = randomize_case(&packet_name(&hello, EncodingKind::Base32));
+        let response = resolve(&handler, &query(&name, RecordType::AAAA)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError, "{name}");
+        assert!(!response.answers().is_empty());
+        assert!(handler.sessions.lock().await.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn downstream_answers_keep_the_case_the_query_was_asked_in() {
+        let handler = handler(None);
+        let keys = handshake(&handler, 1).await;
+        handler.sessions.lock().await.get_mut(&1).unwrap().outbox.push_back(b"queued".to_vec());
+
+        for (sequence, kind) in DownstreamKind::ALL.into_iter().enumerate() {
+            let poll = Packet::Poll {
+                session_id: 1,
+                sealed: keys.upstream.seal(&poll_to_bytes(sequence as u32, &[]), &1u32.to_be_bytes()),
+            };
+            let name = randomize_case(&format!("{}.", packet_name(&poll, EncodingKind::Base32)));
+            let response = resolve(&handler, &query(&name, kind.record_type())).await;
+
+            assert_eq!(response.response_code(), ResponseCode::NoError, "{kind}: {name}");
+            assert!(!response.answers().is_empty(), "{kind}");
+            assert!(response.answers().iter().all(|record| record.name().to_ascii() == name), "{kind}: {name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn downstream_answers_fill_the_advertised_payload_size() {
+        let handler = handler(None);
+        let mut session_id = 0;
+        for size in [512, 1232, 4096] {
+            for kind in DownstreamKind::ALL {
+                session_id += 1;
+                let keys = handshake(&handler, session_id).await;
+                handler.sessions.lock().await.get_mut(&session_id).unwrap().outbox.push_back(vec![0x5a; 8192]);
+
+                let (header, sealed) = seal_frames(b"ping", session_id, 1, &keys.upstream, Compression::None, 8).unwrap().remove(0);
+                let mut message = query(&packet_name(&Packet::Data { header, sealed }, EncodingKind::Base32), kind.record_type());
+                message.extensions_mut().get_or_insert_with(Edns::new).set_max_payload(size as u16);
+                let response = resolve(&handler, &message).await;
+
+                assert_eq!(response.response_code(), ResponseCode::NoError, "{kind} at {size}");
+                assert!(response.to_vec().unwrap().len() <= size, "{kind} at {size}");
+                // The acknowledgement and the frame after it take every byte the answer has room for
+                let payload = kind.decode(response.answers().iter().map(Record::data), DOMAIN).unwrap();
+                assert_eq!(payload.len(), kind.capacity(DOMAIN, answer_budget(size)), "{kind} at {size}");
+                assert_eq!(payload.len(), ACK_LEN + FRAME_HEADER_LEN + SEAL_OVERHEAD + downstream_capacity(kind, DOMAIN, size));
+            }
+        }
+    }
+}
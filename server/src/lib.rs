@@ -0,0 +1,50 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use color_eyre::Result;
+use hickory_server::ServerFuture;
+use hickory_server::server::RequestHandler;
+use crate::tls::load_tls_config;
+
+pub mod handler;
+pub mod session;
+pub mod tls;
+pub mod zone;
+
+/// Idle TCP connections are closed after this long
+pub const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the listeners ended up, ports left at 0 are picked by the system
+pub struct Listening {
+    /// UDP and TCP, which share the port
+    pub addr: SocketAddr,
+    /// DoT, along with the fingerprint clients pin its certificate by
+    pub tls: Option<(SocketAddr, String)>,
+}
+
+/// Listens over UDP and TCP on `addr`, and over DoT on `tls_addr` when given a PEM certificate and key
+pub async fn listen<H: RequestHandler>(server: &mut ServerFuture<H>, addr: SocketAddr, tls: Option<(SocketAddr, &Path, &Path)>) -> Result<Listening> {
+    let socket = tokio::net::UdpSocket::bind(addr).await?;
+    let addr = socket.local_addr()?;
+    server.register_socket(socket);
+
+    // Same address over TCP, for answers that came back truncated over UDP
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    server.register_listener(listener, TCP_TIMEOUT);
+
+    let tls = match tls {
+        Some((tls_addr, cert, key)) => {
+            let (tls_config, fingerprint) = load_tls_config(cert, key)?;
+            let listener = tokio::net::TcpListener::bind(tls_addr).await?;
+            let tls_addr = listener.local_addr()?;
+            server.register_tls_listener_with_tls_config(listener, TCP_TIMEOUT, tls_config)?;
+            Some((tls_addr, fingerprint))
+        }
+        None => None,
+    };
+
+    Ok(Listening {
+        addr,
+        tls,
+    })
+}
@@ -1,22 +1,35 @@
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use clap::Parser;
 use hickory_server::ServerFuture;
 use inquire::Confirm;
 use reedline::{ExternalPrinter, Reedline, Signal};
-use shared::{print_banner, Action, State};
+use shared::{print_banner, Action, ConnectPassword, EncodingKind, Psk, State};
 
 use color_eyre::Result;
 use tokio::select;
+use tokio::sync::Mutex;
+use server::{
+    handler::{Config, MyHandler},
+    listen,
+    session::{sessions_table, Sessions},
+    zone::Zone,
+};
 use crate::{
-    handler::MyHandler,
-    commands::init_commands,
+    commands::{chat, init_commands},
     prompt::NihilPrompt,
 };
 
 mod commands;
-mod handler;
 mod prompt;
 
+/// How often messages that stopped getting fragments are looked for, on top of whenever a fragment arrives
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+/// What most resolvers take over UDP with EDNS0
+const MAX_RESPONSE_SIZE: usize = 4096;
+
 #[derive(Parser)]
 pub struct CliArgs {
     /// Host Address
@@ -25,9 +38,47 @@ pub struct CliArgs {
     /// Listening Port (Run with root access to use port 53)
     #[arg(short, long, value_parser, default_value = "5053")]
     port: u16,
-    /// XOR Key used to encrypt data
-    #[arg(long, default_value = "sisyphean")]
-    xor_key: String,
+    /// Shared secret that authenticates the key exchange
+    #[arg(long, alias = "xor-key", default_value = "sisyphean")]
+    secret: String,
+    /// Encodings clients are allowed to use (base32, base36, hex, base64url)
+    #[arg(long, value_delimiter = ',', default_value = "base32,base36,hex,base64url")]
+    encodings: Vec<EncodingKind>,
+    /// Tunnel zone delegated to this server (e.g. t.example.lab), queries outside of it and of --zone are refused
+    #[arg(short, long)]
+    domain: Option<String>,
+    /// Zone file (RFC 1035) with the SOA, NS, glue and any other static records of the zone, answered authoritatively.
+    /// Only names under --domain go to the tunnel, which defaults to the zone's origin
+    #[arg(long)]
+    zone: Option<PathBuf>,
+    /// TTL of tunnel answers, 0 keeps resolvers from caching them at all
+    #[arg(long, default_value = "0")]
+    ttl: u32,
+    /// Password clients have to prove they know before the server accepts their data
+    #[arg(long, conflicts_with = "connect_password_file")]
+    connect_password: Option<String>,
+    /// File holding the connect password, keeps it out of the process list
+    #[arg(long)]
+    connect_password_file: Option<PathBuf>,
+    /// Seconds a partly received message is kept around waiting for its missing fragments
+    #[arg(long, default_value = "30")]
+    reassembly_timeout: u64,
+    /// Largest response downstream frames are sized to fill. Over UDP frames shrink to fit the EDNS0 payload size the query advertises
+    /// (512 bytes without EDNS0), so only queries over TCP or TLS get answers this big
+    #[arg(long, default_value_t = MAX_RESPONSE_SIZE)]
+    response_size: usize,
+    /// Turn down clients offering to compress messages
+    #[arg(long)]
+    no_compression: bool,
+    /// PEM certificate for DNS-over-TLS, the DoT listener only starts when this and --tls-key are given
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key belonging to --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Port the DoT listener binds on the same address
+    #[arg(long, default_value = "853")]
+    tls_port: u16,
 }
 
 #[tokio::main]
@@ -43,7 +94,37 @@ async fn main() -> Result<()> {
 
     let app_state = State::new(commands, &printer, sender.clone());
 
-    let handler = MyHandler::new(app_state.sender.clone(), cli_args.xor_key);
+    let password = match (cli_args.connect_password, cli_args.connect_password_file) {
+        (Some(password), _) => Some(password),
+        (None, Some(path)) => Some(std::fs::read_to_string(path)?.trim_end().to_string()),
+        (None, None) => None,
+    };
+    let password = password.map(|password| ConnectPassword::new(&password));
+
+    let sessions = Arc::new(Mutex::new(Sessions::new()));
+    let event_sessions = sessions.clone();
+
+    let zone = cli_args.zone.as_deref().map(Zone::load).transpose()?;
+    let domain = match (cli_args.domain, &zone) {
+        (Some(domain), _) => domain,
+        (None, Some(zone)) => zone.origin().to_string().trim_end_matches('.').to_string(),
+        (None, None) => String::new(),
+    };
+
+    let config = Config {
+        psk: Psk::from_secret(&cli_args.secret),
+        password,
+        encodings: cli_args.encodings,
+        domain,
+        zone,
+        ttl: cli_args.ttl,
+        reassembly_timeout: Duration::from_secs(cli_args.reassembly_timeout),
+        response_size: cli_args.response_size,
+        compression: !cli_args.no_compression,
+    };
+    let handler = MyHandler::new(app_state.sender.clone(), config, sessions);
+    let stats = handler.stats();
+    let reassembler = handler.reassembler();
     let mut server = ServerFuture::new(handler);
 
     let server_addr = SocketAddr::new(
@@ -51,15 +132,21 @@ async fn main() -> Result<()> {
         cli_args.port,
     );
 
-    let socket = tokio::net::UdpSocket::bind(server_addr).await?;
-    server.register_socket(socket);
+    let tls = match (&cli_args.tls_cert, &cli_args.tls_key) {
+        (Some(cert), Some(key)) => Some((SocketAddr::new(cli_args.address, cli_args.tls_port), cert.as_path(), key.as_path())),
+        _ => None,
+    };
+    let listening = listen(&mut server, server_addr, tls).await?;
 
     let mut line_editor = Reedline::create()
         .with_external_printer(printer);
     let mut prompt = NihilPrompt::new(app_state);
 
     print_banner("Server");
-    println!("Up and running at: {}", server_addr);
+    println!("Up and running at: {} (UDP and TCP)", listening.addr);
+    if let Some((tls_addr, fingerprint)) = listening.tls {
+        println!("DNS-over-TLS at: {}, clients pin the certificate with --tls-pin {}", tls_addr, fingerprint);
+    }
 
     let reedline_handle = tokio::task::spawn_blocking(move || -> Result<()> {
         while !prompt.state.exit {
@@ -77,13 +164,17 @@ async fn main() -> Result<()> {
             let sig = line_editor.read_line(&prompt);
             match sig {
                 Ok(Signal::Success(buffer)) => {
+                    if let Some(session_id) = prompt.state.active_session {
+                        chat(&mut prompt.state, session_id, &buffer)?;
+                        continue;
+                    }
+
                     let args = buffer.split_whitespace().collect::<Vec<&str>>();
-                    if !args.is_empty() {
-                        if let Some(cmd) = prompt.state.commands.get(args[0]) {
-                            (cmd.function)(&mut prompt.state, &args[1..])?
-                        }
+                    if let Some(name) = args.first()
+                        && let Some(cmd) = prompt.state.commands.get(name) {
+                        (cmd.function)(&mut prompt.state, &args[1..])?
                     }
-                    prompt.state.printer.print(format!("Processed: {}", buffer).into())?;
+                    prompt.state.printer.print(format!("Processed: {}", buffer))?;
                 },
                 Ok(Signal::CtrlD) => {
                     prompt.state.printer.print("\nAborted!".into())?;
@@ -103,8 +194,16 @@ async fn main() -> Result<()> {
     });
 
     let event_handle = tokio::task::spawn(async move {
+        // Session the console is chatting with, its messages are shown as chat lines
+        let mut focus = None;
+        let mut eviction = tokio::time::interval(EVICTION_INTERVAL);
         loop {
             select! {
+                _ = eviction.tick() => {
+                    for (session_id, message_id) in reassembler.lock().await.evict_expired() {
+                        event_printer.print(format!("Dropped incomplete message {} from session {:08x}", message_id, session_id)).unwrap();
+                    }
+                }
                 maybe_event = receiver.recv() => {
                     match maybe_event {
                         Some(event) => {
@@ -112,7 +211,54 @@ async fn main() -> Result<()> {
                                 Action::Log(msg) => {
                                     event_printer.print(msg).unwrap();
                                 }
-                                Action::TempSend => {}
+                                Action::ListClients => {
+                                    let table = sessions_table(&*event_sessions.lock().await);
+                                    event_printer.print(format!("{}{}", table, stats)).unwrap();
+                                }
+                                Action::Kick(session_id) => {
+                                    let msg = match event_sessions.lock().await.remove(&session_id) {
+                                        Some(_) => {
+                                            // Its cached answers went with it, its partial messages have to go too
+                                            reassembler.lock().await.remove_session(session_id);
+                                            format!("Kicked session {:08x}", session_id)
+                                        }
+                                        None => format!("No session {:08x}", session_id),
+                                    };
+                                    event_printer.print(msg).unwrap();
+                                }
+                                // Only the client sends test messages and receives server ones
+                                Action::TempSend | Action::Received { .. } => {}
+                                Action::Message { session_id, message_id, data } => {
+                                    let msg = if focus == Some(session_id) {
+                                        format!("<{:08x}> {}", session_id, String::from_utf8_lossy(&data))
+                                    } else {
+                                        format!("Message {} from session {:08x}: {}",
+                                                message_id,
+                                                session_id,
+                                                String::from_utf8_lossy(&data))
+                                    };
+                                    event_printer.print(msg).unwrap();
+                                }
+                                Action::Focus(session_id) => {
+                                    focus = session_id;
+                                }
+                                Action::Lookup { session_id, exists } => {
+                                    exists.send(event_sessions.lock().await.contains_key(&session_id)).ok();
+                                }
+                                Action::Send { session_id, data } => {
+                                    let msg = match event_sessions.lock().await.get_mut(&session_id) {
+                                        Some(session) => {
+                                            session.outbox.push_back(data);
+                                            // Chat lines are echoed by the prompt already
+                                            (focus != Some(session_id))
+                                                .then(|| format!("Queued message for session {:08x}, {} waiting", session_id, session.outbox.len()))
+                                        }
+                                        None => Some(format!("No session {:08x}", session_id)),
+                                    };
+                                    if let Some(msg) = msg {
+                                        event_printer.print(msg).unwrap();
+                                    }
+                                }
                             }
                         },
                         _ => break,
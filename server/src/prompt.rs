@@ -18,7 +18,10 @@ impl NihilPrompt {
 
 impl Prompt for NihilPrompt {
     fn render_prompt_left(&self) -> Cow<'_, str> {
-        Cow::Owned("nihil_elegans".into())
+        match self.state.active_session {
+            Some(session_id) => Cow::Owned(format!("nihil_elegans [{:08x}]", session_id)),
+            None => Cow::Owned("nihil_elegans".into()),
+        }
     }
 
     fn render_prompt_right(&self) -> Cow<'_, str> {
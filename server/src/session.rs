@@ -0,0 +1,179 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Instant;
+use color_eyre::eyre::eyre;
+use shared::{
+    downstream_capacity, frame_to_bytes, seal_frames,
+    Compression, DownstreamKind, FragmentId, Freshness, Keys, ReplayWindow, SendWindow, ServerHello, CHALLENGE_LEN, MAX_ATTEMPTS,
+    PUBLIC_KEY_LEN, RETRANSMIT_TIMEOUT, SEND_WINDOW,
+};
+
+/// Query names whose answers are kept for exact retransmits, from the client or a resolver in between
+pub const ANSWER_CACHE_LEN: usize = 64;
+
+pub struct Session {
+    pub client_public: [u8; PUBLIC_KEY_LEN],
+    /// Kept so a retransmitted hello gets the exact same answer
+    pub server_hello: ServerHello,
+    pub keys: Keys,
+    /// Set when the server has a connect password, cleared once the client answers it
+    pub challenge: Option<[u8; CHALLENGE_LEN]>,
+    /// Agreed on in the handshake, applies both ways
+    pub compression: Compression,
+    /// Messages waiting to go to the client, not yet cut into frames
+    pub outbox: VecDeque<Vec<u8>>,
+    /// Frames of the message currently going out, resent until the client acknowledges them in its polls
+    frames: SendWindow<Vec<u8>>,
+    next_message_id: u16,
+    /// Query names and frames of the latest downstream answers, handed out again when the same query comes back
+    answers: VecDeque<(String, Vec<u8>)>,
+    /// Sequence numbers of the polls taken so far
+    polls: ReplayWindow,
+    /// Ids of the messages received whole so far
+    delivered: ReplayWindow,
+    /// Where the last query came from, which is the resolver rather than the client when tunnelling through one
+    pub addr: SocketAddr,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    /// Payload bytes, not counting framing or encryption
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Messages received whole
+    pub messages: u64,
+    /// Polls and fragments dropped as replays
+    pub replays: u64,
+}
+
+impl Session {
+    pub fn new(client_public: [u8; PUBLIC_KEY_LEN], server_hello: ServerHello, keys: Keys, challenge: Option<[u8; CHALLENGE_LEN]>, compression: Compression, addr: SocketAddr) -> Self {
+        let now = Instant::now();
+        Self {
+            client_public,
+            server_hello,
+            keys,
+            challenge,
+            compression,
+            outbox: VecDeque::new(),
+            frames: SendWindow::new(SEND_WINDOW, RETRANSMIT_TIMEOUT, MAX_ATTEMPTS),
+            next_message_id: 0,
+            answers: VecDeque::new(),
+            polls: ReplayWindow::new(),
+            delivered: ReplayWindow::new(),
+            addr,
+            first_seen: now,
+            last_seen: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            messages: 0,
+            replays: 0,
+        }
+    }
+
+    pub fn touch(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+        self.last_seen = Instant::now();
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.challenge.is_none()
+    }
+
+    /// Stops resending frames the client says it has
+    pub fn ack(&mut self, acks: &[FragmentId]) {
+        for ack in acks {
+            self.frames.ack(*ack);
+        }
+    }
+
+    /// Whether the query `name` was answered recently, repeating it is a retransmit rather than a replay
+    pub fn answered(&self, name: &str) -> bool {
+        self.answers.iter().any(|(answered, _)| answered == name)
+    }
+
+    /// Keeps the frame a query was answered with, for when it comes again
+    pub fn remember(&mut self, name: &str, frame: Vec<u8>) {
+        if self.answers.len() >= ANSWER_CACHE_LEN {
+            self.answers.pop_front();
+        }
+        self.answers.push_back((name.to_string(), frame));
+    }
+
+    /// Takes a poll's sequence number, false (and counted) when it was seen before or is too old to tell
+    pub fn accept_poll(&mut self, sequence: u32) -> bool {
+        let fresh = self.polls.mark(sequence as u64) == Freshness::New;
+        if !fresh {
+            self.replays += 1;
+        }
+        fresh
+    }
+
+    /// Whether a fragment belongs to a message still coming in, rather than one delivered already or too old to tell.
+    /// Anything but new is counted as a replay
+    pub fn message_freshness(&mut self, message_id: u16) -> Freshness {
+        let freshness = self.delivered.check(self.delivered.extend(message_id));
+        if freshness != Freshness::New {
+            self.replays += 1;
+        }
+        freshness
+    }
+
+    /// Notes a message as received whole, its fragments are replays from now on
+    pub fn delivered(&mut self, message_id: u16) {
+        self.delivered.mark(self.delivered.extend(message_id));
+    }
+
+    /// Next frame to answer the query `name` with, empty when nothing is waiting.
+    /// Messages are only cut into frames once the one before has been acknowledged, sized for the record type the client asks with.
+    /// Asking with the same name again gets the same frame, so an answer truncated over UDP or retransmitted isn't lost
+    pub fn next_frame(&mut self, session_id: u32, name: &str, kind: DownstreamKind, domain: &str, response_size: usize) -> color_eyre::Result<Vec<u8>> {
+        if let Some((_, frame)) = self.answers.iter().find(|(answered, _)| answered == name) {
+            return Ok(frame.clone());
+        }
+
+        if self.frames.is_empty()
+            && let Some(message) = self.outbox.pop_front() {
+            let chunk_len = downstream_capacity(kind, domain, response_size);
+            if chunk_len == 0 {
+                return Err(eyre!("{} answers have no room for data under this zone", kind));
+            }
+
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            for (header, sealed) in seal_frames(&message, session_id, self.next_message_id, &self.keys.downstream, self.compression, chunk_len)? {
+                self.frames.push(FragmentId::of(&header), frame_to_bytes(&header, &sealed));
+            }
+        }
+
+        let frame = self.frames.next_to_send()?
+            .map(|(_, frame)| frame)
+            .unwrap_or_default();
+        self.remember(name, frame.clone());
+
+        Ok(frame)
+    }
+}
+
+/// Every session the server knows of, by session id
+pub type Sessions = HashMap<u32, Session>;
+
+/// Renders the sessions as a table, most recently seen first
+pub fn sessions_table(sessions: &Sessions) -> String {
+    let mut rows = sessions.iter().collect::<Vec<_>>();
+    rows.sort_by_key(|(_, session)| session.last_seen.elapsed());
+
+    let mut table = format!("{:<10}{:<24}{:>12}{:>12}{:>10}{:>10}{:>10}{:>10}\n",
+                            "Session", "Address", "First seen", "Last seen", "In", "Out", "Messages", "Replays");
+    for (session_id, session) in rows {
+        let _ = writeln!(table, "{:<10}{:<24}{:>11}s{:>11}s{:>10}{:>10}{:>10}{:>10}",
+                         format!("{:08x}", session_id),
+                         session.addr,
+                         session.first_seen.elapsed().as_secs(),
+                         session.last_seen.elapsed().as_secs(),
+                         session.bytes_in,
+                         session.bytes_out,
+                         session.messages,
+                         session.replays);
+    }
+
+    table
+}
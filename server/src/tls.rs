@@ -0,0 +1,30 @@
+use std::path::Path;
+use std::sync::Arc;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use rustls::ServerConfig;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use shared::cert_fingerprint;
+
+/// Builds the DoT listener's TLS config from PEM files, along with the fingerprint clients pin the certificate by
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> Result<(Arc<ServerConfig>, String)> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| eyre!("Could not read certificate {}: {}", cert_path.display(), e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| eyre!("Could not parse certificate {}: {}", cert_path.display(), e))?;
+    let leaf = certs.first()
+        .ok_or_else(|| eyre!("No certificate in {}", cert_path.display()))?;
+    let fingerprint = cert_fingerprint(leaf);
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| eyre!("Could not read private key {}: {}", key_path.display(), e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok((Arc::new(config), fingerprint))
+}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_proto::serialize::txt::Parser;
+
+/// Static records the server answers for authoritatively, loaded from a standard zone file
+pub struct Zone {
+    origin: LowerName,
+    soa: Record,
+    /// Every record in the zone by owner name
+    nodes: BTreeMap<LowerName, Vec<Record>>,
+}
+
+/// What the zone has for a query
+pub enum Lookup {
+    /// Records for the name, along with glue for any nameservers or mail exchangers among them
+    Found {
+        answers: Vec<Record>,
+        additionals: Vec<Record>,
+    },
+    /// The name exists but has nothing of the type asked for
+    NoData,
+    NxDomain,
+}
+
+impl Zone {
+    /// Reads an RFC 1035 zone file, which has to set its `$ORIGIN` (or only use absolute names) and carry an SOA at the apex
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("Could not read zone file {}: {}", path.display(), e))?;
+        let (origin, record_sets) = Parser::new(contents, Some(path.to_path_buf()), None)
+            .parse()
+            .map_err(|e| eyre!("Could not parse zone file {}: {}", path.display(), e))?;
+
+        let mut nodes = BTreeMap::<LowerName, Vec<Record>>::new();
+        for record_set in record_sets.into_values() {
+            nodes.entry(LowerName::new(record_set.name()))
+                .or_default()
+                .extend(record_set.records_without_rrsigs().cloned());
+        }
+
+        let origin = LowerName::from(origin);
+        let soa = nodes.get(&origin)
+            .and_then(|records| records.iter().find(|record| record.record_type() == RecordType::SOA))
+            .cloned()
+            .ok_or_else(|| eyre!("Zone file {} has no SOA record for {}", path.display(), origin))?;
+
+        Ok(Self {
+            origin,
+            soa,
+            nodes,
+        })
+    }
+
+    pub fn origin(&self) -> &LowerName {
+        &self.origin
+    }
+
+    /// Goes in the authority section of negative answers, so resolvers know how long to cache them
+    pub fn soa(&self) -> &Record {
+        &self.soa
+    }
+
+    /// Whether the name falls under the zone at all
+    pub fn contains(&self, name: &LowerName) -> bool {
+        self.origin.zone_of(name)
+    }
+
+    /// Whether the zone has records of its own at the name
+    pub fn has_records(&self, name: &LowerName) -> bool {
+        self.nodes.contains_key(name)
+    }
+
+    pub fn lookup(&self, name: &LowerName, query_type: RecordType) -> Lookup {
+        let Some(records) = self.nodes.get(name) else {
+            // Names with records below them exist even without records of their own
+            return if self.nodes.keys().any(|node| name.zone_of(node)) {
+                Lookup::NoData
+            } else {
+                Lookup::NxDomain
+            };
+        };
+
+        let mut answers = records.iter()
+            .filter(|record| query_type == RecordType::ANY || record.record_type() == query_type)
+            .cloned()
+            .collect::<Vec<_>>();
+        // An alias stands in for every other type at its name
+        if answers.is_empty() {
+            answers.extend(records.iter().filter(|record| record.record_type() == RecordType::CNAME).cloned());
+        }
+        if answers.is_empty() {
+            return Lookup::NoData;
+        }
+
+        let additionals = answers.iter()
+            .filter_map(|record| match record.data() {
+                RData::NS(ns) => Some(&ns.0),
+                RData::MX(mx) => Some(mx.exchange()),
+                _ => None,
+            })
+            .flat_map(|target| self.addresses(target))
+            .collect();
+
+        Lookup::Found {
+            answers,
+            additionals,
+        }
+    }
+
+    /// Glue addresses the zone has for a name
+    fn addresses(&self, name: &Name) -> Vec<Record> {
+        self.nodes.get(&LowerName::new(name))
+            .map(|records| records.iter()
+                .filter(|record| matches!(record.record_type(), RecordType::A | RecordType::AAAA))
+                .cloned()
+                .collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,242 @@
+use color_eyre::eyre::eyre;
+
+const B32_CHARSET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const B32_PAD: u8 = b'-';
+const INVALID: u8 = 0xFF;
+
+const B32_DECODE_TABLE: [u8; 256] = {
+    let mut table = [INVALID; 256];
+    let mut i = 0;
+    // Either case, names come back from resolvers in whatever case they like
+    while i < B32_CHARSET.len() {
+        table[B32_CHARSET[i] as usize] = i as u8;
+        table[B32_CHARSET[i].to_ascii_lowercase() as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Number of characters `b32_encode` produces for `len` bytes of input, padding included
+pub const fn b32_encoded_len(len: usize) -> usize {
+    let chars = (len * 8).div_ceil(5);
+    chars.div_ceil(8) * 8
+}
+
+/// Incremental base32 encoder, output is identical to `b32_encode` over the concatenated input
+#[derive(Debug, Default, Clone)]
+pub struct B32Encoder {
+    buffer: u16,
+    bits: u32,
+    written: usize,
+}
+
+impl B32Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        out.reserve((data.len() * 8 + self.bits as usize) / 5);
+        for &byte in data {
+            self.buffer = (self.buffer << 8) | byte as u16;
+            self.bits += 8;
+            while self.bits >= 5 {
+                self.bits -= 5;
+                out.push(B32_CHARSET[((self.buffer >> self.bits) & 0x1F) as usize]);
+                self.written += 1;
+            }
+        }
+    }
+
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        if self.bits > 0 {
+            // Left-align the leftover bits, the low (5 - bits) bits are zero padding
+            out.push(B32_CHARSET[((self.buffer << (5 - self.bits)) & 0x1F) as usize]);
+            self.written += 1;
+        }
+
+        while !self.written.is_multiple_of(8) {
+            out.push(B32_PAD);
+            self.written += 1;
+        }
+    }
+}
+
+/// Incremental base32 decoder, accepts the same input as `b32_decode` split at any point
+#[derive(Debug, Default, Clone)]
+pub struct B32Decoder {
+    buffer: u16,
+    bits: u32,
+    padded: bool,
+}
+
+impl B32Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) -> color_eyre::Result<()> {
+        out.reserve(data.len() * 5 / 8);
+        for &ch in data {
+            if ch == B32_PAD {
+                self.padded = true;
+                continue;
+            }
+            if self.padded {
+                return Err(eyre!("Invalid base32 data: {} after padding", ch as char));
+            }
+
+            let value = B32_DECODE_TABLE[ch as usize];
+            if value == INVALID {
+                return Err(eyre!("Invalid base32 char: {}", ch as char));
+            }
+
+            self.buffer = (self.buffer << 5) | value as u16;
+            self.bits += 5;
+            if self.bits >= 8 {
+                self.bits -= 8;
+                out.push((self.buffer >> self.bits) as u8);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Leftover bits are the encoder's zero padding, anything past 4 bits means a dangling char
+    pub fn finish(self) -> color_eyre::Result<()> {
+        if self.bits >= 5 {
+            return Err(eyre!("Invalid base32 data: truncated input"));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn b32_encode(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(b32_encoded_len(data.len()));
+    let mut encoder = B32Encoder::new();
+    encoder.update(data, &mut result);
+    encoder.finish(&mut result);
+
+    result
+}
+
+pub fn b32_decode(data: &[u8]) -> color_eyre::Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(data.len() * 5 / 8);
+    let mut decoder = B32Decoder::new();
+    decoder.update(data, &mut result)?;
+    decoder.finish()?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::rand_core::RngCore;
+    use chacha20poly1305::aead::OsRng;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+
+    /// Cuts `len` into a handful of random chunk sizes, empty chunks included
+    fn random_splits(len: usize) -> Vec<usize> {
+        let mut splits = (0..4).map(|_| OsRng.next_u32() as usize % (len + 1)).collect::<Vec<_>>();
+        splits.push(0);
+        splits.push(len);
+        splits.sort_unstable();
+        splits
+    }
+
+    fn encode_in_chunks(data: &[u8], splits: &[usize]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = B32Encoder::new();
+        for window in splits.windows(2) {
+            encoder.update(&data[window[0]..window[1]], &mut out);
+        }
+        encoder.finish(&mut out);
+        out
+    }
+
+    fn decode_in_chunks(data: &[u8], splits: &[usize]) -> color_eyre::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut decoder = B32Decoder::new();
+        for window in splits.windows(2) {
+            decoder.update(&data[window[0]..window[1]], &mut out)?;
+        }
+        decoder.finish()?;
+        Ok(out)
+    }
+
+    #[test]
+    fn rfc4648_vectors() {
+        let vectors: [(&[u8], &[u8]); 7] = [
+            (b"", b""),
+            (b"f", b"MY------"),
+            (b"fo", b"MZXQ----"),
+            (b"foo", b"MZXW6---"),
+            (b"foob", b"MZXW6YQ-"),
+            (b"fooba", b"MZXW6YTB"),
+            (b"foobar", b"MZXW6YTBOI------"),
+        ];
+
+        for (plain, encoded) in vectors {
+            assert_eq!(b32_encode(plain), encoded);
+            assert_eq!(b32_decode(encoded).unwrap(), plain);
+        }
+    }
+
+    #[test]
+    fn round_trips_every_length() {
+        for len in 0..=4096 {
+            let data = random_bytes(len);
+            let encoded = b32_encode(&data);
+            assert_eq!(encoded.len(), b32_encoded_len(len), "length {len}");
+            assert_eq!(b32_decode(&encoded).unwrap(), data, "length {len}");
+        }
+    }
+
+    #[test]
+    fn streaming_matches_one_shot_at_every_split() {
+        for len in 0..=64 {
+            let data = random_bytes(len);
+            let encoded = b32_encode(&data);
+            for split in 0..=len {
+                assert_eq!(encode_in_chunks(&data, &[0, split, len]), encoded, "length {len}, split {split}");
+            }
+            for split in 0..=encoded.len() {
+                assert_eq!(decode_in_chunks(&encoded, &[0, split, encoded.len()]).unwrap(), data, "length {len}, split {split}");
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_matches_one_shot_at_random_splits() {
+        for len in 0..=4096 {
+            let data = random_bytes(len);
+            let encoded = b32_encode(&data);
+            let splits = random_splits(len);
+            assert_eq!(encode_in_chunks(&data, &splits), encoded, "length {len}, splits {splits:?}");
+            let splits = random_splits(encoded.len());
+            assert_eq!(decode_in_chunks(&encoded, &splits).unwrap(), data, "length {len}, splits {splits:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_either_case() {
+        assert_eq!(b32_decode(b"mzxw6ytboi------").unwrap(), b"foobar");
+        assert_eq!(b32_decode(b"mZxW6yTbOi------").unwrap(), b"foobar");
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(b32_decode(b"MZXW6YT=").is_err());
+        assert!(b32_decode(b"MZXW1YTB").is_err());
+        assert!(b32_decode(b"MY--MY--").is_err());
+        // A lone trailing char carries fewer than 8 bits
+        assert!(b32_decode(b"MZXW6YTBO").is_err());
+    }
+}
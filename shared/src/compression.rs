@@ -0,0 +1,100 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use color_eyre::eyre::eyre;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use crate::FLAG_COMPRESSED;
+
+/// Messages never inflate past this, so a small compressed frame can't be used to exhaust memory
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// How messages are compressed before they're sealed, agreed on in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Compression {
+    None,
+    #[default]
+    Deflate,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 2] = [Compression::None, Compression::Deflate];
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    /// Unknown schemes read as none, so a newer peer falls back instead of failing the handshake.
+    /// The hello tag is checked over the byte as sent, before it goes through here
+    pub fn from_byte(byte: u8) -> Self {
+        Self::ALL.into_iter()
+            .find(|compression| compression.to_byte() == byte)
+            .unwrap_or(Compression::None)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    /// Compresses a message, returning it with the frame flags to send it under.
+    /// Messages compression doesn't shrink go out as they are
+    pub fn compress(self, data: &[u8]) -> color_eyre::Result<(Vec<u8>, u8)> {
+        let compressed = match self {
+            Compression::None => return Ok((data.to_vec(), 0)),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        };
+
+        if compressed.len() < data.len() {
+            Ok((compressed, FLAG_COMPRESSED))
+        } else {
+            Ok((data.to_vec(), 0))
+        }
+    }
+
+    /// Undoes `compress` for a message that arrived under `flags`
+    pub fn decompress(self, data: Vec<u8>, flags: u8) -> color_eyre::Result<Vec<u8>> {
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(data);
+        }
+
+        match self {
+            Compression::None => Err(eyre!("Got a compressed message without having agreed on compression")),
+            Compression::Deflate => {
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(data.as_slice())
+                    .take(MAX_MESSAGE_LEN as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > MAX_MESSAGE_LEN {
+                    return Err(eyre!("Compressed message inflates past {} bytes", MAX_MESSAGE_LEN));
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|compression| compression.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown compression: {s} (expected one of none, deflate)"))
+    }
+}
@@ -0,0 +1,245 @@
+use std::fmt;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use crate::{Encoding, Hex};
+
+pub const NONCE_LEN: usize = 12;
+/// Hex characters of the cache-busting label in front of every query name
+pub const NONCE_LABEL_LEN: usize = 8;
+pub const TAG_LEN: usize = 16;
+/// Bytes `Cipher::seal` adds on top of the plaintext
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Handshake messages carry HMAC-SHA256 truncated to this many bytes
+pub const AUTH_TAG_LEN: usize = 16;
+pub const CHALLENGE_LEN: usize = 16;
+
+const KDF_SALT: &[u8] = b"nihil_elegans";
+const PASSWORD_KDF_SALT: &[u8] = b"nihil_elegans connect";
+const KDF_ROUNDS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// Too short to even hold a nonce and tag
+    Truncated,
+    /// Authentication failed, the data was altered or sealed under another key
+    Tampered,
+    /// The peer's handshake was not signed with the shared secret
+    Unauthenticated,
+    /// The challenge response did not match the connect password
+    WrongPassword,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::Truncated => f.write_str("Sealed data is truncated"),
+            CryptoError::Tampered => f.write_str("Sealed data failed authentication"),
+            CryptoError::Unauthenticated => f.write_str("Handshake failed authentication"),
+            CryptoError::WrongPassword => f.write_str("Wrong connect password"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// ChaCha20-Poly1305 with a fresh random nonce carried in front of every sealed message
+#[derive(Clone)]
+pub struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    /// Returns `nonce || ciphertext || tag`
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = Vec::with_capacity(plaintext.len() + SEAL_OVERHEAD);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(
+            self.0.encrypt(&nonce, Payload { msg: plaintext, aad })
+                .expect("ChaCha20-Poly1305 only fails on messages past 256 GiB")
+        );
+
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(CryptoError::Truncated);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| CryptoError::Tampered)
+    }
+}
+
+/// One key per direction, so the two sides never seal under the same key
+#[derive(Clone)]
+pub struct Keys {
+    pub upstream: Cipher,
+    pub downstream: Cipher,
+}
+
+impl Keys {
+    /// Session keys come from the X25519 exchange alone, salted with both public keys
+    fn from_exchange(shared_secret: &[u8], client_public: &[u8; 32], server_public: &[u8; 32]) -> Self {
+        let mut salt = [0u8; 2 * PUBLIC_KEY_LEN];
+        salt[..PUBLIC_KEY_LEN].copy_from_slice(client_public);
+        salt[PUBLIC_KEY_LEN..].copy_from_slice(server_public);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+        let mut upstream = [0u8; 32];
+        let mut downstream = [0u8; 32];
+        hkdf.expand(b"nihil_elegans upstream", &mut upstream)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        hkdf.expand(b"nihil_elegans downstream", &mut downstream)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        Self {
+            upstream: Cipher::new(&upstream),
+            downstream: Cipher::new(&downstream),
+        }
+    }
+}
+
+fn stretch(secret: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(secret.as_bytes(), salt, KDF_ROUNDS, &mut key);
+    key
+}
+
+fn mac(key: &[u8; 32], parts: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+fn tag(key: &[u8; 32], parts: &[&[u8]]) -> [u8; AUTH_TAG_LEN] {
+    let mut tag = [0u8; AUTH_TAG_LEN];
+    tag.copy_from_slice(&mac(key, parts).finalize().into_bytes()[..AUTH_TAG_LEN]);
+    tag
+}
+
+fn verify(key: &[u8; 32], parts: &[&[u8]], tag: &[u8], error: CryptoError) -> Result<(), CryptoError> {
+    mac(key, parts)
+        .verify_truncated_left(tag)
+        .map_err(|_| error)
+}
+
+/// Key stretched out of the configured secret, only ever used to authenticate handshakes
+#[derive(Clone)]
+pub struct Psk([u8; 32]);
+
+impl Psk {
+    pub fn from_secret(secret: &str) -> Self {
+        Self(stretch(secret, KDF_SALT))
+    }
+
+    /// Covers the compression offer too, so it can't be downgraded on the way
+    pub fn client_hello_tag(&self, session_id: u32, client_public: &[u8; 32], compression: u8) -> [u8; AUTH_TAG_LEN] {
+        tag(&self.0, &[b"client hello", &session_id.to_be_bytes(), client_public, &[compression]])
+    }
+
+    pub fn verify_client_hello(&self, session_id: u32, client_public: &[u8; 32], compression: u8, tag: &[u8]) -> Result<(), CryptoError> {
+        verify(&self.0, &[b"client hello", &session_id.to_be_bytes(), client_public, &[compression]], tag, CryptoError::Unauthenticated)
+    }
+
+    /// Covers the client's key and the flags and challenge too, so a server hello can't be replayed into another exchange
+    pub fn server_hello_tag(&self, session_id: u32, client_public: &[u8; 32], server_public: &[u8; 32], tagged: &[u8]) -> [u8; AUTH_TAG_LEN] {
+        tag(&self.0, &[b"server hello", &session_id.to_be_bytes(), client_public, server_public, tagged])
+    }
+
+    pub fn verify_server_hello(&self, session_id: u32, client_public: &[u8; 32], server_public: &[u8; 32], tagged: &[u8], tag: &[u8]) -> Result<(), CryptoError> {
+        verify(&self.0, &[b"server hello", &session_id.to_be_bytes(), client_public, server_public, tagged], tag, CryptoError::Unauthenticated)
+    }
+}
+
+/// Key stretched out of the server's connect password, clients prove they know it without ever sending it
+#[derive(Clone)]
+pub struct ConnectPassword([u8; 32]);
+
+impl ConnectPassword {
+    pub fn new(password: &str) -> Self {
+        Self(stretch(password, PASSWORD_KDF_SALT))
+    }
+
+    pub fn respond(&self, session_id: u32, challenge: &[u8; CHALLENGE_LEN]) -> [u8; AUTH_TAG_LEN] {
+        tag(&self.0, &[b"connect", &session_id.to_be_bytes(), challenge])
+    }
+
+    pub fn verify(&self, session_id: u32, challenge: &[u8; CHALLENGE_LEN], response: &[u8]) -> Result<(), CryptoError> {
+        verify(&self.0, &[b"connect", &session_id.to_be_bytes(), challenge], response, CryptoError::WrongPassword)
+    }
+}
+
+pub fn new_challenge() -> [u8; CHALLENGE_LEN] {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// One side's ephemeral X25519 key pair, consumed when the session keys are derived
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public: [u8; PUBLIC_KEY_LEN],
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret).to_bytes();
+
+        Self {
+            secret,
+            public,
+        }
+    }
+
+    pub fn public(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public
+    }
+
+    /// Both sides call this with the keys in the same client/server order and end up with the same `Keys`
+    pub fn finish(self, client_public: &[u8; 32], server_public: &[u8; 32]) -> Result<Keys, CryptoError> {
+        let peer = if *client_public == self.public { server_public } else { client_public };
+        let shared_secret = self.secret.diffie_hellman(&PublicKey::from(*peer));
+        if !shared_secret.was_contributory() {
+            return Err(CryptoError::Unauthenticated);
+        }
+
+        Ok(Keys::from_exchange(shared_secret.as_bytes(), client_public, server_public))
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SHA-256 of a DER encoded certificate in hex, DoT clients pin the server's certificate by it
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    Hex.encode(&Sha256::digest(der))
+}
+
+pub fn new_session_id() -> u32 {
+    OsRng.next_u32()
+}
+
+/// Random label that makes every query name unique, so resolvers can't answer it from their cache
+pub fn new_nonce_label() -> String {
+    let mut nonce = [0u8; NONCE_LABEL_LEN / 2];
+    OsRng.fill_bytes(&mut nonce);
+    Hex.encode(&nonce)
+}
@@ -0,0 +1,334 @@
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use color_eyre::eyre::eyre;
+use hickory_proto::rr::rdata::{AAAA, CNAME, MX, NULL, TXT};
+use hickory_proto::rr::{Name, RData, RecordType};
+use crate::{
+    in_zone, label_chars, pack_octets, strip_zone, unpack_octets, zone_len,
+    Base32, Base64Url, Encoding, MAX_LABEL_LEN, MAX_NAME_LEN,
+};
+
+/// Largest response a plain DNS message over UDP can be
+pub const UDP_RESPONSE_SIZE: usize = 512;
+
+/// Root name, type, class, TTL and RDATA length of the EDNS0 OPT record, carried without options
+const OPT_RECORD_LEN: usize = 11;
+
+/// Room left for answers in a response of `response_size` bytes, assuming the longest possible question and an OPT record
+pub const fn answer_budget(response_size: usize) -> usize {
+    response_size.saturating_sub(12 + MAX_NAME_LEN + 2 + 4 + OPT_RECORD_LEN)
+}
+
+/// Owner name pointer, type, class, TTL and RDATA length in front of every answer record
+const RECORD_OVERHEAD: usize = 12;
+/// Every payload starts with its length, since most record types pad what they carry
+const LENGTH_PREFIX_LEN: usize = 2;
+const TXT_STRING_LEN: usize = 255;
+const AAAA_RECORD_LEN: usize = RECORD_OVERHEAD + 16;
+
+/// Record types the server can carry data back to the client in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DownstreamKind {
+    Txt,
+    Cname,
+    Mx,
+    Aaaa,
+    Null,
+}
+
+impl DownstreamKind {
+    pub const ALL: [DownstreamKind; 5] = [
+        DownstreamKind::Txt,
+        DownstreamKind::Cname,
+        DownstreamKind::Mx,
+        DownstreamKind::Aaaa,
+        DownstreamKind::Null,
+    ];
+
+    pub fn record_type(self) -> RecordType {
+        match self {
+            DownstreamKind::Txt => RecordType::TXT,
+            DownstreamKind::Cname => RecordType::CNAME,
+            DownstreamKind::Mx => RecordType::MX,
+            DownstreamKind::Aaaa => RecordType::AAAA,
+            DownstreamKind::Null => RecordType::NULL,
+        }
+    }
+
+    pub fn from_record_type(record_type: RecordType) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.record_type() == record_type)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DownstreamKind::Txt => "txt",
+            DownstreamKind::Cname => "cname",
+            DownstreamKind::Mx => "mx",
+            DownstreamKind::Aaaa => "aaaa",
+            DownstreamKind::Null => "null",
+        }
+    }
+
+    /// Most payload bytes that fit in the answers to one query, given `budget` bytes of answer section
+    pub fn capacity(self, domain: &str, budget: usize) -> usize {
+        let Some(rdata_len) = budget.checked_sub(RECORD_OVERHEAD) else {
+            return 0;
+        };
+
+        let raw = match self {
+            DownstreamKind::Null => rdata_len,
+            DownstreamKind::Txt => {
+                Base64Url.max_decoded_len(rdata_len - rdata_len.div_ceil(TXT_STRING_LEN + 1))
+            }
+            DownstreamKind::Aaaa => (budget / AAAA_RECORD_LEN).min(256) * (16 - 1),
+            DownstreamKind::Cname => {
+                let text_len = MAX_NAME_LEN.min(rdata_len.saturating_sub(2));
+                Base32.max_decoded_len(label_chars(text_len.saturating_sub(zone_len(domain))))
+            }
+            DownstreamKind::Mx => {
+                let record_len = RECORD_OVERHEAD + 2 + MAX_LABEL_LEN + 1 + zone_len(domain) + 1;
+                Base32.max_decoded_len((budget / record_len).min(u16::MAX as usize) * MAX_LABEL_LEN)
+            }
+        };
+
+        raw.saturating_sub(LENGTH_PREFIX_LEN)
+    }
+
+    /// Spreads a payload over answer records of this type, names are put under the tunnel zone.
+    /// Fails when it's more than fits in `budget` bytes of answer section
+    pub fn encode(self, payload: &[u8], domain: &str, budget: usize) -> color_eyre::Result<Vec<RData>> {
+        let capacity = self.capacity(domain, budget);
+        if payload.len() > capacity {
+            return Err(eyre!("Downstream payload of {} bytes doesn't fit in {} answers, which take {}", payload.len(), self, capacity));
+        }
+        let length = u16::try_from(payload.len())
+            .map_err(|_| eyre!("Downstream payload of {} bytes is too long", payload.len()))?;
+        let mut bytes = Vec::with_capacity(LENGTH_PREFIX_LEN + payload.len());
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(payload);
+
+        let rdatas = match self {
+            DownstreamKind::Null => vec![RData::NULL(NULL::with(bytes))],
+            DownstreamKind::Txt => {
+                let encoded = Base64Url.encode(&bytes);
+                let strings = encoded.as_bytes()
+                    .chunks(TXT_STRING_LEN)
+                    .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                    .collect();
+                vec![RData::TXT(TXT::new(strings))]
+            }
+            DownstreamKind::Aaaa => pack_octets(&bytes)
+                .into_iter()
+                .map(|octets| RData::AAAA(AAAA::from(Ipv6Addr::from(octets))))
+                .collect(),
+            DownstreamKind::Cname => {
+                let labels = Base32.encode(&bytes);
+                let labels = labels.as_bytes()
+                    .chunks(MAX_LABEL_LEN)
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>();
+                vec![RData::CNAME(CNAME(to_name(&in_zone(&labels.join("."), domain))?))]
+            }
+            DownstreamKind::Mx => {
+                let encoded = Base32.encode(&bytes);
+                encoded.as_bytes()
+                    .chunks(MAX_LABEL_LEN)
+                    .enumerate()
+                    .map(|(index, label)| {
+                        let exchange = to_name(&in_zone(&String::from_utf8_lossy(label), domain))?;
+                        Ok(RData::MX(MX::new(index as u16, exchange)))
+                    })
+                    .collect::<color_eyre::Result<_>>()?
+            }
+        };
+
+        Ok(rdatas)
+    }
+
+    /// Puts a payload back together from the answers, ignoring records of other types
+    pub fn decode<'a>(self, rdatas: impl IntoIterator<Item = &'a RData>, domain: &str) -> color_eyre::Result<Vec<u8>> {
+        let rdatas = rdatas.into_iter().collect::<Vec<_>>();
+
+        let bytes = match self {
+            DownstreamKind::Null => rdatas.iter()
+                .find_map(|rdata| match rdata {
+                    RData::NULL(null) => Some(null.anything().to_vec()),
+                    _ => None,
+                })
+                .ok_or_else(|| eyre!("No NULL record in answer"))?,
+            DownstreamKind::Txt => {
+                let txt = rdatas.iter()
+                    .find_map(|rdata| match rdata {
+                        RData::TXT(txt) => Some(txt),
+                        _ => None,
+                    })
+                    .ok_or_else(|| eyre!("No TXT record in answer"))?;
+                let encoded = txt.txt_data()
+                    .iter()
+                    .map(|string| String::from_utf8_lossy(string))
+                    .collect::<String>();
+                Base64Url.decode(&encoded)?
+            }
+            DownstreamKind::Aaaa => {
+                let records = rdatas.iter()
+                    .filter_map(|rdata| match rdata {
+                        RData::AAAA(aaaa) => Some(aaaa.0.octets()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                unpack_octets(&records)?
+            }
+            DownstreamKind::Cname => {
+                let name = rdatas.iter()
+                    .find_map(|rdata| match rdata {
+                        RData::CNAME(cname) => Some(from_name(&cname.0)),
+                        _ => None,
+                    })
+                    .ok_or_else(|| eyre!("No CNAME record in answer"))?;
+                let labels = strip_zone(&name, domain)
+                    .ok_or_else(|| eyre!("CNAME target {name} is outside of the tunnel zone"))?;
+                Base32.decode(&labels.replace('.', ""))?
+            }
+            DownstreamKind::Mx => {
+                let mut exchanges = rdatas.iter()
+                    .filter_map(|rdata| match rdata {
+                        RData::MX(mx) => Some((mx.preference(), from_name(mx.exchange()))),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                exchanges.sort_by_key(|(preference, _)| *preference);
+
+                let mut encoded = String::new();
+                for (_, exchange) in &exchanges {
+                    encoded.push_str(strip_zone(exchange, domain)
+                        .ok_or_else(|| eyre!("MX exchange {exchange} is outside of the tunnel zone"))?);
+                }
+                Base32.decode(&encoded)?
+            }
+        };
+
+        if bytes.len() < LENGTH_PREFIX_LEN {
+            return Err(eyre!("Downstream payload too short: {} bytes", bytes.len()));
+        }
+        let length = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        bytes.get(LENGTH_PREFIX_LEN..LENGTH_PREFIX_LEN + length)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| eyre!("Downstream payload is cut short"))
+    }
+}
+
+impl fmt::Display for DownstreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DownstreamKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown record type: {s} (expected one of txt, cname, mx, aaaa, null)"))
+    }
+}
+
+fn to_name(name: &str) -> color_eyre::Result<Name> {
+    let mut name = Name::from_labels(name.split('.').map(str::as_bytes))?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+fn from_name(name: &Name) -> String {
+    name.iter()
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::rand_core::RngCore;
+    use chacha20poly1305::aead::OsRng;
+    use hickory_proto::op::{Edns, Message, Query};
+    use hickory_proto::rr::Record;
+
+    const DOMAIN: &str = "t.example.lab";
+    const RESPONSE_SIZES: [usize; 3] = [UDP_RESPONSE_SIZE, 1232, 4096];
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+
+    /// Longest question the tunnel zone can be asked, which `answer_budget` leaves room for
+    fn longest_name() -> Name {
+        let mut labels = vec!["a".repeat(MAX_LABEL_LEN); 3];
+        labels.push("a".repeat(MAX_NAME_LEN - 3 * (MAX_LABEL_LEN + 1) - zone_len(DOMAIN) - 1));
+        to_name(&in_zone(&labels.join("."), DOMAIN)).unwrap()
+    }
+
+    /// How long the response carrying `rdatas` is on the wire, EDNS0 included
+    fn response_len(name: &Name, rdatas: Vec<RData>) -> usize {
+        let mut message = Message::query();
+        message.add_query(Query::query(name.clone(), RecordType::A));
+        message.add_answers(rdatas.into_iter().map(|rdata| Record::from_rdata(name.clone(), 0, rdata)));
+        message.set_edns(Edns::new());
+        message.to_vec().unwrap().len()
+    }
+
+    #[test]
+    fn round_trips_at_capacity() {
+        let name = longest_name();
+        for kind in DownstreamKind::ALL {
+            for response_size in RESPONSE_SIZES {
+                let capacity = kind.capacity(DOMAIN, answer_budget(response_size));
+                assert!(capacity > 0, "{kind}, {response_size} bytes");
+
+                for len in [0, 1, capacity / 2, capacity] {
+                    let payload = random_bytes(len);
+                    let rdatas = kind.encode(&payload, DOMAIN, answer_budget(response_size)).unwrap();
+                    assert_eq!(kind.decode(&rdatas, DOMAIN).unwrap(), payload, "{kind}, {response_size} bytes, length {len}");
+                    assert!(response_len(&name, rdatas) <= response_size, "{kind}, {response_size} bytes, length {len}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_more_than_capacity() {
+        for kind in DownstreamKind::ALL {
+            for response_size in RESPONSE_SIZES {
+                let capacity = kind.capacity(DOMAIN, answer_budget(response_size));
+                assert!(kind.encode(&random_bytes(capacity + 1), DOMAIN, answer_budget(response_size)).is_err(), "{kind}, {response_size} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_records_in_any_order() {
+        for kind in [DownstreamKind::Aaaa, DownstreamKind::Mx] {
+            let payload = random_bytes(kind.capacity(DOMAIN, answer_budget(1232)));
+            let mut rdatas = kind.encode(&payload, DOMAIN, answer_budget(1232)).unwrap();
+            assert!(rdatas.len() > 1, "{kind}");
+            let third = rdatas.len() / 3;
+            rdatas.reverse();
+            rdatas.rotate_left(third);
+
+            assert_eq!(kind.decode(&rdatas, DOMAIN).unwrap(), payload, "{kind}");
+        }
+    }
+
+    #[test]
+    fn ignores_other_record_types() {
+        let payload = random_bytes(32);
+        let mut rdatas = DownstreamKind::Txt.encode(&payload, DOMAIN, answer_budget(512)).unwrap();
+        rdatas.insert(0, RData::NULL(NULL::with(vec![1, 2, 3])));
+
+        assert_eq!(DownstreamKind::Txt.decode(&rdatas, DOMAIN).unwrap(), payload);
+        assert!(DownstreamKind::Cname.decode(&rdatas, DOMAIN).is_err());
+    }
+}
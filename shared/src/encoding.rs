@@ -0,0 +1,395 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use color_eyre::eyre::eyre;
+use crate::{b32_decode, b32_encode};
+
+/// A way of turning payload bytes into label-safe characters and back
+pub trait Encoding: Send + Sync {
+    fn kind(&self) -> EncodingKind;
+    /// Every character this encoding can emit, used to probe whether a resolver path mangles any of them
+    fn alphabet(&self) -> &'static str;
+    fn case_sensitive(&self) -> bool;
+    fn encoded_len(&self, len: usize) -> usize;
+    fn encode(&self, data: &[u8]) -> String;
+    fn decode(&self, data: &str) -> color_eyre::Result<Vec<u8>>;
+
+    /// Settles the case of encoded data, which resolvers randomize on the way (0x20). Case-insensitive encodings
+    /// read it in their own lowercase alphabet, case-sensitive ones can't undo it and take it as it came
+    fn normalize_case<'a>(&self, data: &'a str) -> Cow<'a, str> {
+        if self.case_sensitive() || !data.bytes().any(|ch| ch.is_ascii_uppercase()) {
+            Cow::Borrowed(data)
+        } else {
+            Cow::Owned(data.to_ascii_lowercase())
+        }
+    }
+
+    /// Most payload bytes that fit in `chars` characters
+    fn max_decoded_len(&self, chars: usize) -> usize {
+        let mut len = 0;
+        while self.encoded_len(len + 1) <= chars {
+            len += 1;
+        }
+        len
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EncodingKind {
+    Base32,
+    Base36,
+    Hex,
+    Base64Url,
+}
+
+impl EncodingKind {
+    /// Densest first, which is the order the client probes them in
+    pub const ALL: [EncodingKind; 4] = [
+        EncodingKind::Base64Url,
+        EncodingKind::Base36,
+        EncodingKind::Base32,
+        EncodingKind::Hex,
+    ];
+
+    pub fn encoding(self) -> &'static dyn Encoding {
+        match self {
+            EncodingKind::Base32 => &Base32,
+            EncodingKind::Base36 => &Base36,
+            EncodingKind::Hex => &Hex,
+            EncodingKind::Base64Url => &Base64Url,
+        }
+    }
+
+    /// Digits survive every resolver, so they are used to tag which encoding a name carries
+    pub fn tag(self) -> char {
+        match self {
+            EncodingKind::Base32 => '0',
+            EncodingKind::Base36 => '1',
+            EncodingKind::Hex => '2',
+            EncodingKind::Base64Url => '3',
+        }
+    }
+
+    pub fn from_tag(tag: char) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.tag() == tag)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EncodingKind::Base32 => "base32",
+            EncodingKind::Base36 => "base36",
+            EncodingKind::Hex => "hex",
+            EncodingKind::Base64Url => "base64url",
+        }
+    }
+}
+
+impl fmt::Display for EncodingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EncodingKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown encoding: {s} (expected one of base32, base36, hex, base64url)"))
+    }
+}
+
+/// RFC 4648 base32, lowercase and without padding
+pub struct Base32;
+
+impl Encoding for Base32 {
+    fn kind(&self) -> EncodingKind {
+        EncodingKind::Base32
+    }
+
+    fn alphabet(&self) -> &'static str {
+        "abcdefghijklmnopqrstuvwxyz234567"
+    }
+
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+
+    fn encoded_len(&self, len: usize) -> usize {
+        (len * 8).div_ceil(5)
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        let mut encoded = b32_encode(data);
+        encoded.retain(|&ch| ch != b'-');
+        encoded.make_ascii_lowercase();
+
+        // The base32 charset is pure ASCII
+        String::from_utf8(encoded).unwrap_or_default()
+    }
+
+    fn decode(&self, data: &str) -> color_eyre::Result<Vec<u8>> {
+        b32_decode(data.as_bytes())
+    }
+}
+
+/// The payload as one big-endian number, left-padded with zeros to a width fixed by the payload length
+pub struct Base36;
+
+const BASE36_CHARSET: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+impl Encoding for Base36 {
+    fn kind(&self) -> EncodingKind {
+        EncodingKind::Base36
+    }
+
+    fn alphabet(&self) -> &'static str {
+        "0123456789abcdefghijklmnopqrstuvwxyz"
+    }
+
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+
+    fn encoded_len(&self, len: usize) -> usize {
+        // Each extra byte adds ~1.55 chars, so distinct payload lengths never share an encoded length
+        (len as f64 * 8.0 / 36f64.log2()).ceil() as usize
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        let mut number = data.to_vec();
+        let mut digits = vec![b'0'; self.encoded_len(data.len())];
+
+        for digit in digits.iter_mut().rev() {
+            let mut rem = 0u32;
+            for byte in number.iter_mut() {
+                let acc = (rem << 8) | *byte as u32;
+                *byte = (acc / 36) as u8;
+                rem = acc % 36;
+            }
+            *digit = BASE36_CHARSET[rem as usize];
+        }
+
+        String::from_utf8(digits).unwrap_or_default()
+    }
+
+    fn decode(&self, data: &str) -> color_eyre::Result<Vec<u8>> {
+        let len = self.max_decoded_len(data.len());
+        if self.encoded_len(len) != data.len() {
+            return Err(eyre!("Invalid base36 data: bad length {}", data.len()));
+        }
+
+        let mut number = vec![0u8; len];
+        for ch in data.bytes() {
+            let value = match ch.to_ascii_lowercase() {
+                c @ b'0'..=b'9' => c - b'0',
+                c @ b'a'..=b'z' => c - b'a' + 10,
+                _ => return Err(eyre!("Invalid base36 char: {}", ch as char)),
+            };
+
+            let mut carry = value as u32;
+            for byte in number.iter_mut().rev() {
+                let acc = *byte as u32 * 36 + carry;
+                *byte = acc as u8;
+                carry = acc >> 8;
+            }
+            if carry != 0 {
+                return Err(eyre!("Invalid base36 data: value overflows {} bytes", len));
+            }
+        }
+
+        Ok(number)
+    }
+}
+
+pub struct Hex;
+
+impl Encoding for Hex {
+    fn kind(&self) -> EncodingKind {
+        EncodingKind::Hex
+    }
+
+    fn alphabet(&self) -> &'static str {
+        "0123456789abcdef"
+    }
+
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+
+    fn encoded_len(&self, len: usize) -> usize {
+        len * 2
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        let charset = self.alphabet().as_bytes();
+        let mut encoded = String::with_capacity(data.len() * 2);
+        for byte in data {
+            encoded.push(charset[(byte >> 4) as usize] as char);
+            encoded.push(charset[(byte & 0x0F) as usize] as char);
+        }
+        encoded
+    }
+
+    fn decode(&self, data: &str) -> color_eyre::Result<Vec<u8>> {
+        if !data.len().is_multiple_of(2) {
+            return Err(eyre!("Invalid hex data: odd length {}", data.len()));
+        }
+
+        let nibble = |ch: u8| (ch as char).to_digit(16)
+            .map(|value| value as u8)
+            .ok_or_else(|| eyre!("Invalid hex char: {}", ch as char));
+
+        data.as_bytes()
+            .chunks(2)
+            .map(|pair| Ok((nibble(pair[0])? << 4) | nibble(pair[1])?))
+            .collect()
+    }
+}
+
+/// RFC 4648 base64url without padding, only usable on paths that keep the case of names intact
+pub struct Base64Url;
+
+const BASE64URL_CHARSET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+impl Encoding for Base64Url {
+    fn kind(&self) -> EncodingKind {
+        EncodingKind::Base64Url
+    }
+
+    fn alphabet(&self) -> &'static str {
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_"
+    }
+
+    fn case_sensitive(&self) -> bool {
+        true
+    }
+
+    fn encoded_len(&self, len: usize) -> usize {
+        (len * 8).div_ceil(6)
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        let mut encoded = String::with_capacity(self.encoded_len(data.len()));
+        let mut buffer = 0u16;
+        let mut bits = 0;
+
+        for &byte in data {
+            buffer = (buffer << 8) | byte as u16;
+            bits += 8;
+            while bits >= 6 {
+                bits -= 6;
+                encoded.push(BASE64URL_CHARSET[((buffer >> bits) & 0x3F) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(BASE64URL_CHARSET[((buffer << (6 - bits)) & 0x3F) as usize] as char);
+        }
+
+        encoded
+    }
+
+    fn decode(&self, data: &str) -> color_eyre::Result<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(data.len() * 6 / 8);
+        let mut buffer = 0u16;
+        let mut bits = 0;
+
+        for ch in data.bytes() {
+            let value = match ch {
+                b'A'..=b'Z' => ch - b'A',
+                b'a'..=b'z' => ch - b'a' + 26,
+                b'0'..=b'9' => ch - b'0' + 52,
+                b'-' => 62,
+                b'_' => 63,
+                _ => return Err(eyre!("Invalid base64url char: {}", ch as char)),
+            };
+
+            buffer = (buffer << 6) | value as u16;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                decoded.push((buffer >> bits) as u8);
+            }
+        }
+        if bits >= 6 {
+            return Err(eyre!("Invalid base64url data: truncated input"));
+        }
+
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::rand_core::RngCore;
+    use chacha20poly1305::aead::OsRng;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        OsRng.fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn round_trips_every_length() {
+        for kind in EncodingKind::ALL {
+            let encoding = kind.encoding();
+            for len in 0..=256 {
+                let data = random_bytes(len);
+                let encoded = encoding.encode(&data);
+                assert_eq!(encoded.len(), encoding.encoded_len(len), "{kind}, length {len}");
+                assert!(encoded.chars().all(|ch| encoding.alphabet().contains(ch)), "{kind}, length {len}: {encoded}");
+                assert_eq!(encoding.decode(&encoded).unwrap(), data, "{kind}, length {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn max_decoded_len_is_the_most_that_fits() {
+        for kind in EncodingKind::ALL {
+            let encoding = kind.encoding();
+            for chars in 0..=300 {
+                let len = encoding.max_decoded_len(chars);
+                assert!(encoding.encoded_len(len) <= chars, "{kind}, {chars} chars");
+                assert!(encoding.encoded_len(len + 1) > chars, "{kind}, {chars} chars");
+            }
+        }
+    }
+
+    #[test]
+    fn normalize_case_only_folds_case_insensitive_encodings() {
+        for kind in EncodingKind::ALL {
+            let encoding = kind.encoding();
+            for len in 0..=64 {
+                let data = random_bytes(len);
+                let encoded = encoding.encode(&data);
+                let upper = encoded.to_ascii_uppercase();
+                let normalized = encoding.normalize_case(&upper);
+
+                if encoding.case_sensitive() {
+                    assert_eq!(normalized, upper, "{kind}, length {len}");
+                } else {
+                    assert_eq!(normalized, encoded, "{kind}, length {len}");
+                    assert_eq!(encoding.decode(&normalized).unwrap(), data, "{kind}, length {len}");
+                }
+                // Already settled, nothing is copied
+                assert!(matches!(encoding.normalize_case(&encoded), Cow::Borrowed(_)), "{kind}, length {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        // No payload length encodes to 3 base36 chars
+        assert!(Base36.decode("000").is_err());
+        assert!(Base36.decode("zz").is_err());
+        assert!(Base36.decode("0-").is_err());
+        assert!(Hex.decode("abc").is_err());
+        assert!(Hex.decode("zz").is_err());
+        // A lone trailing char carries fewer than 8 bits
+        assert!(Base64Url.decode("A").is_err());
+        assert!(Base64Url.decode("A.").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use color_eyre::eyre::eyre;
+
+/// Bumped whenever the layout of frames changes, peers drop frames from other versions
+pub const PROTOCOL_VERSION: u8 = 3;
+/// Version, session id, message id, fragment index, fragment count and flags
+pub const FRAME_HEADER_LEN: usize = 12;
+/// Set on every fragment of a message that was compressed before it was sealed
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Says which message a fragment belongs to and where it goes, also used as the AAD its payload is sealed under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub session_id: u32,
+    pub message_id: u16,
+    pub fragment_index: u16,
+    pub fragment_count: u16,
+    /// Bit flags, see `FLAG_COMPRESSED`
+    pub flags: u8,
+}
+
+impl FrameHeader {
+    pub fn new(session_id: u32, message_id: u16, fragment_index: u16, fragment_count: u16) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            session_id,
+            message_id,
+            fragment_index,
+            fragment_count,
+            flags: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_LEN] {
+        let mut bytes = [0u8; FRAME_HEADER_LEN];
+        bytes[0] = self.version;
+        bytes[1..5].copy_from_slice(&self.session_id.to_be_bytes());
+        bytes[5..7].copy_from_slice(&self.message_id.to_be_bytes());
+        bytes[7..9].copy_from_slice(&self.fragment_index.to_be_bytes());
+        bytes[9..11].copy_from_slice(&self.fragment_count.to_be_bytes());
+        bytes[11] = self.flags;
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.len() < FRAME_HEADER_LEN {
+            return Err(eyre!("Frame header too short: {} bytes", bytes.len()));
+        }
+        if bytes[0] != PROTOCOL_VERSION {
+            return Err(eyre!("Unsupported protocol version {} (expected {})", bytes[0], PROTOCOL_VERSION));
+        }
+
+        let header = Self {
+            version: bytes[0],
+            session_id: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+            message_id: u16::from_be_bytes([bytes[5], bytes[6]]),
+            fragment_index: u16::from_be_bytes([bytes[7], bytes[8]]),
+            fragment_count: u16::from_be_bytes([bytes[9], bytes[10]]),
+            flags: bytes[11],
+        };
+        if header.fragment_index >= header.fragment_count {
+            return Err(eyre!("Fragment {} out of range for a {} fragment message", header.fragment_index, header.fragment_count));
+        }
+
+        Ok(header)
+    }
+}
@@ -1,9 +1,37 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::Write;
 use color_eyre::eyre::eyre;
 use reedline::ExternalPrinter;
 use tokio::sync::mpsc::Sender;
 
+mod base32;
+mod compression;
+mod crypto;
+mod downstream;
+mod encoding;
+mod frame;
+mod packet;
+mod reassembly;
+mod reliability;
+mod replay;
+
+pub use base32::{b32_decode, b32_encode, b32_encoded_len, B32Decoder, B32Encoder};
+pub use compression::{Compression, MAX_MESSAGE_LEN};
+pub use crypto::{
+    cert_fingerprint, new_challenge, new_nonce_label, new_session_id, Cipher, ConnectPassword, CryptoError, Handshake, Keys, Psk,
+    AUTH_TAG_LEN, CHALLENGE_LEN, NONCE_LABEL_LEN, NONCE_LEN, PUBLIC_KEY_LEN, SEAL_OVERHEAD, TAG_LEN,
+};
+pub use downstream::{answer_budget, DownstreamKind, UDP_RESPONSE_SIZE};
+pub use encoding::{Base32, Base36, Base64Url, Encoding, EncodingKind, Hex};
+pub use frame::{FrameHeader, FLAG_COMPRESSED, FRAME_HEADER_LEN, PROTOCOL_VERSION};
+pub use packet::{pack_octets, unpack_octets, Packet, ServerHello, DATA_HEADER_LEN, PACKET_HEADER_LEN};
+pub use reassembly::{Inserted, Reassembler};
+pub use reliability::{
+    acks_from_bytes, acks_to_bytes, poll_from_bytes, poll_to_bytes, FragmentId, SendWindow, ACK_LEN, MAX_ATTEMPTS, RETRANSMIT_TIMEOUT,
+    SEND_WINDOW,
+};
+pub use replay::{Freshness, ReplayWindow, REPLAY_WINDOW, SEQUENCE_LEN};
+
 pub fn print_banner(mode: &'static str) {
     let banner_top = vec![
         "\x1B[1m\x1B[31m\x1B[38;5;88m",
@@ -22,115 +50,292 @@ pub fn print_banner(mode: &'static str) {
     println!("\x1B[0m{}                    \x1B[1m\x1B[31m\x1B[38;5;88m`-'\x1B[0m{:>12}", mode, format!("v{}", env!("CARGO_PKG_VERSION")));
 }
 
-const B32_CHARSET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+/// RFC 1035 limits, in the dotted text form with no trailing dot
+pub const MAX_LABEL_LEN: usize = 63;
+pub const MAX_NAME_LEN: usize = 253;
+const PROBE_PREFIX: char = 'p';
+
+/// Length the tunnel zone adds to a name, dot included
+fn zone_len(domain: &str) -> usize {
+    match domain.trim_matches('.') {
+        "" => 0,
+        domain => domain.len() + 1,
+    }
+}
+
+/// Characters of data that fit in `text_len` characters of name, every full label of data costs a dot on top
+fn label_chars(text_len: usize) -> usize {
+    text_len - text_len / (MAX_LABEL_LEN + 1)
+}
 
+/// Characters left for encoded data once the nonce label, the tag label and the tunnel zone are in the name
+fn data_chars(domain: &str) -> usize {
+    MAX_NAME_LEN.checked_sub(NONCE_LABEL_LEN + 1 + 2 + zone_len(domain))
+        .map_or(0, label_chars)
+}
 
-pub fn b32_encode(data: &[u8]) -> color_eyre::Result<Vec<u8>> {
-    if data.is_empty() {
-        return Ok(Vec::new());
+/// Most packet bytes, headers included, that fit in a single name under `domain`
+fn max_packet_len(domain: &str, encoding: &dyn Encoding) -> usize {
+    encoding.max_decoded_len(data_chars(domain))
+}
+
+/// Most message bytes a single data query can carry under `domain`, after framing and sealing
+pub fn capacity(domain: &str, encoding: &dyn Encoding) -> usize {
+    max_packet_len(domain, encoding).saturating_sub(DATA_HEADER_LEN + SEAL_OVERHEAD)
+}
+
+pub fn encrypt(data: &[u8], session_id: u32, message_id: u16, cipher: &Cipher, compression: Compression, encoding: &dyn Encoding, domain: &str) -> color_eyre::Result<Vec<String>> {
+    let mut domain_names: Vec<String> = Vec::new();
+
+    let chunk_len = capacity(domain, encoding);
+    if chunk_len == 0 {
+        return Err(eyre!("Tunnel zone {domain} leaves no room for data in a name"));
     }
 
-    let mut bin_data = String::with_capacity(data.len() * 8);
-    for byte in data {
-        write!(bin_data, "{:08b}", byte)?;
+    for (header, sealed) in seal_frames(data, session_id, message_id, cipher, compression, chunk_len)? {
+        domain_names.push(encode_packet(&Packet::Data { header, sealed }, encoding, domain)?);
     }
 
-    let rem = bin_data.len() % 5;
-    if rem > 0 {
-        for _ in 0..rem {
-            bin_data.push('0');
+    Ok(domain_names)
+}
+
+/// Compresses a message when that shrinks it, then cuts it into fragments of at most `chunk_len` bytes,
+/// each sealed on its own with its header as AAD so the other side can open it without seeing the rest
+pub fn seal_frames(data: &[u8], session_id: u32, message_id: u16, cipher: &Cipher, compression: Compression, chunk_len: usize) -> color_eyre::Result<Vec<(FrameHeader, Vec<u8>)>> {
+    let (data, flags) = compression.compress(data)?;
+    let data = data.as_slice();
+    // An empty message still takes one fragment
+    let chunks = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(chunk_len.max(1)).collect()
+    };
+    let fragment_count = u16::try_from(chunks.len())
+        .map_err(|_| eyre!("Message of {} bytes needs more than {} fragments", data.len(), u16::MAX))?;
+
+    Ok(chunks.into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut header = FrameHeader::new(session_id, message_id, index as u16, fragment_count);
+            header.flags = flags;
+            (header, cipher.seal(chunk, &header.to_bytes()))
+        })
+        .collect())
+}
+
+/// Most message bytes one downstream answer of at most `response_size` bytes can carry,
+/// leaving room for the acknowledgement answers to data queries start with
+pub fn downstream_capacity(kind: DownstreamKind, domain: &str, response_size: usize) -> usize {
+    kind.capacity(domain, answer_budget(response_size)).saturating_sub(ACK_LEN + FRAME_HEADER_LEN + SEAL_OVERHEAD)
+}
+
+/// A frame as it travels downstream, header then sealed fragment
+pub fn frame_to_bytes(header: &FrameHeader, sealed: &[u8]) -> Vec<u8> {
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(sealed);
+    bytes
+}
+
+/// Opens a frame from `frame_to_bytes`
+pub fn open_frame(bytes: &[u8], cipher: &Cipher) -> color_eyre::Result<(FrameHeader, Vec<u8>)> {
+    let header = FrameHeader::from_bytes(bytes)?;
+    let fragment = cipher.open(&bytes[FRAME_HEADER_LEN..], &header.to_bytes())?;
+
+    Ok((header, fragment))
+}
+
+/// Opens every fragment of one message, in whatever order the names come in
+pub fn decrypt(data: Vec<String>, cipher: &Cipher, domain: &str) -> color_eyre::Result<Vec<u8>> {
+    let mut fragments = Vec::with_capacity(data.len());
+
+    for name in data {
+        let name = strip_zone(&name, domain)
+            .ok_or_else(|| eyre!("{name} is outside of the tunnel zone"))?;
+        match decode_packet(name)? {
+            Packet::Data { header, sealed } => {
+                fragments.push((header, cipher.open(&sealed, &header.to_bytes())?));
+            }
+            packet => return Err(eyre!("Expected a data packet, got {packet:?}")),
         }
     }
 
-    let mut result = Vec::new();
-    for chunk in bin_data.as_bytes().chunks(5) {
-        let chunk_str = std::str::from_utf8(chunk)?;
-        let index = usize::from_str_radix(chunk_str, 2)?;
-        result.push(B32_CHARSET[index])
+    fragments.sort_by_key(|(header, _)| header.fragment_index);
+    let fragment_count = fragments.len();
+    let mut decrypted_data: Vec<u8> = Vec::new();
+    for (index, (header, mut fragment)) in fragments.into_iter().enumerate() {
+        if header.fragment_index as usize != index || header.fragment_count as usize != fragment_count {
+            return Err(eyre!("Fragments of message {} are missing or duplicated", header.message_id));
+        }
+        decrypted_data.append(&mut fragment);
     }
 
-    while !result.len().is_multiple_of(8) {
-        result.push(b'-')
-    }
+    Ok(decrypted_data)
+}
+
+/// Encodes a packet into a name: the encoding tag label, the encoded packet split into labels, then the tunnel zone
+pub fn encode_packet(packet: &Packet, encoding: &dyn Encoding, domain: &str) -> color_eyre::Result<String> {
+    let encoded = encoding.encode(&packet.to_bytes());
+    let labels = encoded.as_bytes()
+        .chunks(MAX_LABEL_LEN)
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
 
-    Ok(result)
+    let name = in_zone(&format!("{}.{}", encoding.kind().tag(), labels.join(".")), domain);
+    validate_name(&name)?;
+
+    Ok(name)
 }
 
-pub fn b32_decode(data: &[u8]) -> color_eyre::Result<Vec<u8>> {
-    if data.is_empty() {
-        return Ok(Vec::new());
+/// Checks a name against the label and name length limits resolvers enforce
+pub fn validate_name(name: &str) -> color_eyre::Result<()> {
+    let name = name.trim_end_matches('.');
+    if name.len() > MAX_NAME_LEN {
+        return Err(eyre!("Name is {} characters long, the limit is {}", name.len(), MAX_NAME_LEN));
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(eyre!("Label {label:?} has to be 1 to {} characters long", MAX_LABEL_LEN));
+        }
     }
 
-    let data_str = std::str::from_utf8(data).map_err(|_| eyre!("Invalid data in base32"))?
-        .trim_end_matches("-");
+    Ok(())
+}
 
-    let mut bin_chunks = String::with_capacity(data_str.len() * 5);
-    for ch in data_str.chars() {
-        let index = B32_CHARSET
-            .iter()
-            .position(|&c| c == ch as u8)
-            .ok_or_else(|| eyre!("Invalid base32 char: {ch}"))?;
+/// Expects the tunnel zone to already be stripped off with `strip_zone`
+pub fn decode_packet(name: &str) -> color_eyre::Result<Packet> {
+    let encoding = name_encoding(name)
+        .ok_or_else(|| eyre!("Missing encoding tag in {name}"))?
+        .encoding();
+
+    let encoded = name.split('.').skip(1).collect::<String>();
+    Packet::from_bytes(&encoding.decode(&encoding.normalize_case(&encoded))?)
+}
 
-        write!(bin_chunks, "{:05b}", index)?;
+/// Puts a name under the tunnel zone, an empty zone leaves it as is
+pub fn in_zone(name: &str, domain: &str) -> String {
+    match domain.trim_matches('.') {
+        "" => name.to_string(),
+        domain => format!("{name}.{domain}"),
     }
+}
 
-    let mut result = Vec::new();
-    for chunk in bin_chunks.as_bytes().chunks(8) {
-        if chunk.len() == 8 {
-            let chunk_str = std::str::from_utf8(chunk)?;
-            let byte_value = u8::from_str_radix(chunk_str, 2)
-                .map_err(|_| eyre!("Failed to parse chunk"))?;
-            result.push(byte_value);
-        }
+/// Strips the tunnel zone off a query name, `None` when the name falls outside of it.
+/// Resolvers are free to change the case of names, so the zone is matched case-insensitively
+pub fn strip_zone<'a>(name: &'a str, domain: &str) -> Option<&'a str> {
+    let name = name.trim_end_matches('.');
+    let domain = domain.trim_matches('.');
+    if domain.is_empty() {
+        return Some(name);
     }
 
-    Ok(result)
+    let split = name.len().checked_sub(domain.len() + 1)?;
+    let (inner, suffix) = (name.get(..split)?, name.get(split..)?);
+    let suffix = suffix.strip_prefix('.')?;
+
+    (!inner.is_empty() && suffix.eq_ignore_ascii_case(domain)).then_some(inner)
 }
 
-pub fn encrypt(data: &[u8], xor_key: &[u8]) -> color_eyre::Result<Vec<String>> {
-    let mut b32_chunks: Vec<String> = Vec::new();
-    let mut domain_names: Vec<String> = Vec::new();
+/// Strips the cache-busting label the client puts in front of every query name, expects the zone to be stripped already.
+/// The label is hex, in whatever case a resolver gave it
+pub fn strip_nonce(name: &str) -> Option<&str> {
+    let (nonce, name) = name.split_once('.')?;
+    (nonce.len() == NONCE_LABEL_LEN && nonce.bytes().all(|byte| byte.is_ascii_hexdigit())).then_some(name)
+}
 
-    for chunk in data.chunks(35) {
-        let encrypted = chunk.iter().enumerate().map(|(i, &b)| {
-            b ^ xor_key[i % xor_key.len()]
-        }).collect::<Vec<u8>>();
-        let encoded = String::from_utf8(b32_encode(&encrypted)?)?;
-        b32_chunks.push(format!("{}", encoded));
+/// A name with its case settled the way its encoding reads it, so the same query keeps the same name whatever case
+/// a resolver gave it. Expects the zone and nonce label to be stripped already
+pub fn canonical_name(name: &str) -> Cow<'_, str> {
+    match name_encoding(name) {
+        Some(kind) => kind.encoding().normalize_case(name),
+        None => Cow::Borrowed(name),
     }
+}
 
-    for name_chunk in b32_chunks.chunks(4) {
-        domain_names.push(name_chunk.join("."))
-    }
+/// Flips the case of every letter at random, like a resolver doing 0x20
+#[cfg(any(test, feature = "test-util"))]
+pub fn randomize_case(name: &str) -> String {
+    use chacha20poly1305::aead::rand_core::RngCore;
+    use chacha20poly1305::aead::OsRng;
 
-    Ok(domain_names)
+    name.chars()
+        .map(|ch| if OsRng.next_u32() & 1 == 0 { ch.to_ascii_uppercase() } else { ch.to_ascii_lowercase() })
+        .collect()
 }
 
-pub fn decrypt(data: Vec<String>, xor_key: &[u8]) -> color_eyre::Result<Vec<u8>> {
-    let mut b32_chunks: Vec<String> = Vec::new();
-    let mut decrypted_data: Vec<u8> = Vec::new();
+/// Reads the encoding tag off the first label of a name built by `encrypt`
+pub fn name_encoding(name: &str) -> Option<EncodingKind> {
+    let tag_label = name.split('.').next()?;
+    let mut tag = tag_label.chars();
+    match (tag.next(), tag.next()) {
+        (Some(tag), None) => EncodingKind::from_tag(tag),
+        _ => None,
+    }
+}
 
-    for name in data {
-        // Will eventually need to make this trim off the unimportant parts of the domain name
-        for label_chunk in name.split('.').collect::<Vec<&str>>() {
-            b32_chunks.push(label_chunk.to_string())
-        }
+/// A name spelling out the full alphabet of an encoding, which the server checks for mangling
+pub fn probe_name(kind: EncodingKind, domain: &str) -> String {
+    let mut name = format!("{PROBE_PREFIX}{}", kind.tag());
+    for chunk in kind.encoding().alphabet().as_bytes().chunks(32) {
+        name.push('.');
+        name.push_str(&String::from_utf8_lossy(chunk));
     }
 
-    for chunk in b32_chunks {
-        let decoded = b32_decode(chunk.as_bytes())?;
-        let mut decrypted = decoded.iter().enumerate().map(|(i, b)| {
-            b ^ xor_key[i % xor_key.len()]
-        }).collect::<Vec<u8>>();
-        decrypted_data.append(&mut decrypted)
+    in_zone(&name, domain)
+}
+
+/// Returns the probed encoding if the name made it through with its alphabet intact
+pub fn check_probe(name: &str) -> Option<EncodingKind> {
+    let (tag_label, echoed) = name.split_once('.')?;
+    let mut tag = tag_label.chars();
+    if !tag.next()?.eq_ignore_ascii_case(&PROBE_PREFIX) {
+        return None;
+    }
+    let kind = EncodingKind::from_tag(tag.next()?)?;
+    if tag.next().is_some() {
+        return None;
     }
 
-    Ok(decrypted_data)
+    let echoed = echoed.split('.').collect::<String>();
+    let alphabet = kind.encoding().alphabet();
+    let intact = if kind.encoding().case_sensitive() {
+        echoed == alphabet
+    } else {
+        echoed.eq_ignore_ascii_case(alphabet)
+    };
+
+    intact.then_some(kind)
 }
 
 pub enum Action {
     Log(String),
     TempSend,
+    /// Every fragment of a message made it in and it was put back together
+    Message {
+        session_id: u32,
+        message_id: u16,
+        data: Vec<u8>,
+    },
+    /// A whole message came down from the server
+    Received {
+        message_id: u16,
+        data: Vec<u8>,
+    },
+    /// Queues a message to go down to a client in its next answers
+    Send {
+        session_id: u32,
+        data: Vec<u8>,
+    },
+    /// Prints the session registry
+    ListClients,
+    /// Drops a session, its client has to handshake again
+    Kick(u32),
+    /// Switches which session's messages are shown as chat, `None` goes back to the command prompt
+    Focus(Option<u32>),
+    /// Asks whether a session exists, the answer comes back on `exists`
+    Lookup {
+        session_id: u32,
+        exists: tokio::sync::oneshot::Sender<bool>,
+    },
 }
 
 pub struct State {
@@ -140,6 +345,8 @@ pub struct State {
     pub printer: ExternalPrinter<String>,
     pub is_ctrl_c_pressed: bool,
     pub exit: bool,
+    /// Session the console is chatting with, lines go to it instead of being run as commands
+    pub active_session: Option<u32>,
 }
 
 impl State {
@@ -151,6 +358,7 @@ impl State {
             printer: printer.clone(),
             is_ctrl_c_pressed: false,
             exit: false,
+            active_session: None,
         }
     }
 }
@@ -183,3 +391,65 @@ impl Command {
 }
 
 pub type CommandMap = HashMap<&'static str, Command>;
+
+#[cfg(test)]
+mod tests {
+    use chacha20poly1305::aead::rand_core::RngCore;
+    use chacha20poly1305::aead::OsRng;
+    use super::*;
+
+    const DOMAIN: &str = "t.example.lab";
+
+    /// Data names the way `encrypt` makes them, zone stripped like the server does
+    fn data_names(kind: EncodingKind) -> Vec<String> {
+        let mut data = vec![0u8; 600];
+        OsRng.fill_bytes(&mut data);
+        encrypt(&data, 1, 1, &Cipher::new(&[7; 32]), Compression::None, kind.encoding(), DOMAIN)
+            .unwrap()
+            .iter()
+            .map(|name| strip_zone(&randomize_case(name), DOMAIN).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn data_names_survive_case_randomization() {
+        for kind in EncodingKind::ALL.into_iter().filter(|kind| !kind.encoding().case_sensitive()) {
+            for name in data_names(kind) {
+                let packet = decode_packet(&name).unwrap();
+                for _ in 0..8 {
+                    let randomized = randomize_case(&name);
+                    assert_eq!(decode_packet(&randomized).unwrap(), packet, "{kind}: {randomized}");
+                    assert_eq!(canonical_name(&randomized), canonical_name(&name), "{kind}: {randomized}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn probes_survive_case_randomization() {
+        for kind in EncodingKind::ALL {
+            let probe = probe_name(kind, "");
+            assert_eq!(check_probe(&probe), Some(kind));
+
+            let randomized = randomize_case(&probe);
+            if kind.encoding().case_sensitive() {
+                // Caught, so the client never picks it through such a resolver
+                assert_eq!(check_probe(&randomized).is_some(), randomized == probe, "{kind}: {randomized}");
+            } else {
+                assert_eq!(check_probe(&randomized), Some(kind), "{kind}: {randomized}");
+            }
+        }
+    }
+
+    #[test]
+    fn strip_nonce_takes_only_hex_labels() {
+        let label = new_nonce_label();
+        assert_eq!(strip_nonce(&format!("{label}.0.abc")), Some("0.abc"));
+        assert_eq!(strip_nonce(&format!("{}.0.abc", label.to_ascii_uppercase())), Some("0.abc"));
+
+        assert_eq!(strip_nonce("0123456g.0.abc"), None);
+        assert_eq!(strip_nonce("0123456.0.abc"), None);
+        assert_eq!(strip_nonce("012345678.0.abc"), None);
+        assert_eq!(strip_nonce("01234567"), None);
+    }
+}
@@ -0,0 +1,254 @@
+use color_eyre::eyre::eyre;
+use crate::{Compression, FrameHeader, AUTH_TAG_LEN, CHALLENGE_LEN, FRAME_HEADER_LEN, PUBLIC_KEY_LEN};
+
+/// Kind byte plus session id, in front of every packet but data, which carries a whole frame header instead
+pub const PACKET_HEADER_LEN: usize = 5;
+/// Kind byte plus frame header, in front of every data packet
+pub const DATA_HEADER_LEN: usize = 1 + FRAME_HEADER_LEN;
+
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_AUTH: u8 = 3;
+const KIND_POLL: u8 = 4;
+
+const FLAG_CHALLENGE: u8 = 0x01;
+const FLAG_DEFLATE: u8 = 0x02;
+
+/// Bytes of payload per AAAA record, the first of its 16 octets is the record's index
+const OCTETS_PER_RECORD: usize = 15;
+
+/// What a client puts in a query name, before encoding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Client's half of the key exchange, along with the compression it would like to use
+    Hello {
+        session_id: u32,
+        public: [u8; PUBLIC_KEY_LEN],
+        tag: [u8; AUTH_TAG_LEN],
+        /// Byte of the compression offered, kept as sent since the tag covers it and it may name a scheme this side doesn't know
+        compression: u8,
+    },
+    /// One fragment of a message, sealed under the session's upstream key with its header as AAD
+    Data {
+        header: FrameHeader,
+        sealed: Vec<u8>,
+    },
+    /// Answer to the server's password challenge, sealed like data so onlookers can't brute-force it
+    Auth {
+        session_id: u32,
+        sealed: Vec<u8>,
+    },
+    /// Asks for whatever the server has queued. Seals its sequence number, which the server drops replays by, and acknowledgements of downstream fragments
+    Poll {
+        session_id: u32,
+        sealed: Vec<u8>,
+    },
+}
+
+impl Packet {
+    pub fn session_id(&self) -> u32 {
+        match self {
+            Packet::Hello { session_id, .. }
+            | Packet::Auth { session_id, .. }
+            | Packet::Poll { session_id, .. } => *session_id,
+            Packet::Data { header, .. } => header.session_id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Packet::Hello { session_id, public, tag, compression } => {
+                bytes.push(KIND_HELLO);
+                bytes.extend_from_slice(&session_id.to_be_bytes());
+                bytes.extend_from_slice(public);
+                bytes.extend_from_slice(tag);
+                bytes.push(*compression);
+            }
+            Packet::Data { header, sealed } => {
+                bytes.push(KIND_DATA);
+                bytes.extend_from_slice(&header.to_bytes());
+                bytes.extend_from_slice(sealed);
+            }
+            Packet::Auth { session_id, sealed } => {
+                bytes.push(KIND_AUTH);
+                bytes.extend_from_slice(&session_id.to_be_bytes());
+                bytes.extend_from_slice(sealed);
+            }
+            Packet::Poll { session_id, sealed } => {
+                bytes.push(KIND_POLL);
+                bytes.extend_from_slice(&session_id.to_be_bytes());
+                bytes.extend_from_slice(sealed);
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> color_eyre::Result<Self> {
+        if bytes.len() < PACKET_HEADER_LEN {
+            return Err(eyre!("Packet too short: {} bytes", bytes.len()));
+        }
+
+        if bytes[0] == KIND_DATA {
+            return Ok(Packet::Data {
+                header: FrameHeader::from_bytes(&bytes[1..])?,
+                sealed: bytes[DATA_HEADER_LEN..].to_vec(),
+            });
+        }
+
+        let session_id = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let body = &bytes[PACKET_HEADER_LEN..];
+        match bytes[0] {
+            KIND_HELLO => {
+                if body.len() != PUBLIC_KEY_LEN + AUTH_TAG_LEN + 1 {
+                    return Err(eyre!("Hello packet has the wrong length: {} bytes", bytes.len()));
+                }
+                let (public, rest) = body.split_at(PUBLIC_KEY_LEN);
+                let (tag, compression) = rest.split_at(AUTH_TAG_LEN);
+
+                Ok(Packet::Hello {
+                    session_id,
+                    public: public.try_into()?,
+                    tag: tag.try_into()?,
+                    compression: compression[0],
+                })
+            }
+            KIND_AUTH => Ok(Packet::Auth {
+                session_id,
+                sealed: body.to_vec(),
+            }),
+            KIND_POLL => Ok(Packet::Poll {
+                session_id,
+                sealed: body.to_vec(),
+            }),
+            kind => Err(eyre!("Unknown packet kind: {kind}")),
+        }
+    }
+}
+
+/// Server's half of the key exchange, answered in AAAA records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerHello {
+    pub public: [u8; PUBLIC_KEY_LEN],
+    pub tag: [u8; AUTH_TAG_LEN],
+    /// Present when the server wants the connect password proven before it accepts data
+    pub challenge: Option<[u8; CHALLENGE_LEN]>,
+    /// What the server picked out of the client's offer, none when it doesn't support it
+    pub compression: Compression,
+}
+
+impl ServerHello {
+    pub fn to_records(&self) -> Vec<[u8; 16]> {
+        let mut bytes = Vec::with_capacity(PUBLIC_KEY_LEN + AUTH_TAG_LEN + 1 + CHALLENGE_LEN);
+        bytes.extend_from_slice(&self.public);
+        bytes.extend_from_slice(&self.tag);
+        bytes.extend_from_slice(&self.tagged_bytes());
+
+        pack_octets(&bytes)
+    }
+
+    pub fn from_records(records: &[[u8; 16]]) -> color_eyre::Result<Self> {
+        let bytes = unpack_octets(records)?;
+        let fixed_len = PUBLIC_KEY_LEN + AUTH_TAG_LEN + 1;
+        if bytes.len() < fixed_len {
+            return Err(eyre!("Server hello too short: {} bytes", bytes.len()));
+        }
+
+        let flags = bytes[fixed_len - 1];
+        let challenge = if flags & FLAG_CHALLENGE != 0 {
+            let challenge = bytes.get(fixed_len..fixed_len + CHALLENGE_LEN)
+                .ok_or_else(|| eyre!("Server hello is missing its challenge"))?;
+            Some(challenge.try_into()?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            public: bytes[..PUBLIC_KEY_LEN].try_into()?,
+            tag: bytes[PUBLIC_KEY_LEN..PUBLIC_KEY_LEN + AUTH_TAG_LEN].try_into()?,
+            challenge,
+            compression: if flags & FLAG_DEFLATE != 0 { Compression::Deflate } else { Compression::None },
+        })
+    }
+
+    /// Flags byte and challenge, what the server hello tag covers besides the keys
+    pub fn tagged_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.challenge.is_some() {
+            flags |= FLAG_CHALLENGE;
+        }
+        if self.compression == Compression::Deflate {
+            flags |= FLAG_DEFLATE;
+        }
+
+        let mut bytes = vec![flags];
+        bytes.extend_from_slice(self.challenge.as_ref().map_or(&[], |challenge| challenge.as_slice()));
+        bytes
+    }
+}
+
+/// Spreads data over AAAA-sized records, each led by its index since resolvers may shuffle an RRset
+pub fn pack_octets(data: &[u8]) -> Vec<[u8; 16]> {
+    data.chunks(OCTETS_PER_RECORD)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut record = [0u8; 16];
+            record[0] = index as u8;
+            record[1..=chunk.len()].copy_from_slice(chunk);
+            record
+        })
+        .collect()
+}
+
+/// Puts records from `pack_octets` back in order, the last one keeps its zero padding
+pub fn unpack_octets(records: &[[u8; 16]]) -> color_eyre::Result<Vec<u8>> {
+    let mut records = records.to_vec();
+    records.sort_by_key(|record| record[0]);
+
+    let mut data = Vec::with_capacity(records.len() * OCTETS_PER_RECORD);
+    for (index, record) in records.iter().enumerate() {
+        if record[0] as usize != index {
+            return Err(eyre!("Missing record {index} in answer"));
+        }
+        data.extend_from_slice(&record[1..]);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Psk;
+
+    #[test]
+    fn hello_from_a_newer_client_verifies_and_falls_back() {
+        let psk = Psk::from_secret("sisyphean");
+        let public = [7u8; PUBLIC_KEY_LEN];
+        // A scheme this side doesn't know
+        let offered = 0x42;
+        let hello = Packet::Hello {
+            session_id: 1,
+            public,
+            tag: psk.client_hello_tag(1, &public, offered),
+            compression: offered,
+        };
+
+        let Packet::Hello { session_id, public, tag, compression } = Packet::from_bytes(&hello.to_bytes()).unwrap() else {
+            panic!("not a hello");
+        };
+        assert_eq!(compression, offered);
+        assert!(psk.verify_client_hello(session_id, &public, compression, &tag).is_ok());
+        assert_eq!(Compression::from_byte(compression), Compression::None);
+    }
+
+    #[test]
+    fn hello_tag_covers_the_compression_byte() {
+        let psk = Psk::from_secret("sisyphean");
+        let public = [7u8; PUBLIC_KEY_LEN];
+        let tag = psk.client_hello_tag(1, &public, Compression::Deflate.to_byte());
+
+        assert!(psk.verify_client_hello(1, &public, Compression::None.to_byte(), &tag).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use crate::FrameHeader;
+
+/// Messages of one session put together at once, further ones are turned away until these complete or time out
+pub const MAX_PENDING_MESSAGES: usize = 16;
+
+/// Fragments of one message seen so far, only the ones that came in take up room
+struct Pending {
+    fragment_count: u16,
+    fragments: BTreeMap<u16, Vec<u8>>,
+    started: Instant,
+}
+
+/// What became of a fragment handed to the reassembler
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inserted {
+    /// Filed away, its message is still missing fragments
+    Pending,
+    /// It was the last one missing, here's the whole message
+    Complete(Vec<u8>),
+    /// Had it already, or its message was put together already
+    Duplicate,
+    /// Doesn't fit the message it claims to be part of, its index is out of range or the fragment count differs.
+    /// Whatever came in of that message is dropped with it
+    Mismatch,
+    /// Starts a message when its session has too many coming in already, or claims more fragments than a message can have
+    Refused,
+}
+
+/// Puts fragmented messages back together, whatever order their fragments arrive in
+pub struct Reassembler {
+    pending: HashMap<(u32, u16), Pending>,
+    /// Messages handed out recently, retransmitted fragments of them are dropped instead of starting the message over
+    completed: HashMap<(u32, u16), Instant>,
+    timeout: Duration,
+    max_fragments: u16,
+    max_pending: usize,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+            timeout,
+            max_fragments: u16::MAX,
+            max_pending: MAX_PENDING_MESSAGES,
+        }
+    }
+
+    /// Most fragments a message may be cut into, the largest message over the smallest fragments
+    pub fn with_max_fragments(mut self, max_fragments: u16) -> Self {
+        self.max_fragments = max_fragments.max(1);
+        self
+    }
+
+    /// Most messages a single session may have coming in at once
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// Hands back the whole message once its last missing fragment comes in, fragments it has already are reported as duplicates
+    pub fn insert(&mut self, header: FrameHeader, fragment: Vec<u8>) -> Inserted {
+        let key = (header.session_id, header.message_id);
+        if self.completed.contains_key(&key) {
+            return Inserted::Duplicate;
+        }
+        if header.fragment_index >= header.fragment_count {
+            self.pending.remove(&key);
+            return Inserted::Mismatch;
+        }
+
+        if !self.pending.contains_key(&key) {
+            if header.fragment_count > self.max_fragments || self.pending_of(header.session_id) >= self.max_pending {
+                return Inserted::Refused;
+            }
+            self.pending.insert(key, Pending {
+                fragment_count: header.fragment_count,
+                fragments: BTreeMap::new(),
+                started: Instant::now(),
+            });
+        }
+        let Some(pending) = self.pending.get_mut(&key) else {
+            return Inserted::Refused;
+        };
+
+        // A message id reused with another fragment count can't be merged with what's already here
+        if pending.fragment_count != header.fragment_count {
+            self.pending.remove(&key);
+            return Inserted::Mismatch;
+        }
+        if pending.fragments.contains_key(&header.fragment_index) {
+            return Inserted::Duplicate;
+        }
+        pending.fragments.insert(header.fragment_index, fragment);
+
+        if pending.fragments.len() < pending.fragment_count as usize {
+            return Inserted::Pending;
+        }
+
+        let fragments = std::mem::take(&mut pending.fragments);
+        self.pending.remove(&key);
+        self.completed.insert(key, Instant::now());
+
+        Inserted::Complete(fragments.into_values().flatten().collect())
+    }
+
+    /// Messages of a session still coming in
+    fn pending_of(&self, session_id: u32) -> usize {
+        self.pending.keys().filter(|(pending_session, _)| *pending_session == session_id).count()
+    }
+
+    /// Forgets everything of a session, used once it's gone
+    pub fn remove_session(&mut self, session_id: u32) {
+        self.pending.retain(|(pending_session, _), _| *pending_session != session_id);
+        self.completed.retain(|(completed_session, _), _| *completed_session != session_id);
+    }
+
+    /// Drops messages that have been incomplete for longer than the timeout, returning their session and message ids.
+    /// Completed messages stop being remembered after the same timeout
+    pub fn evict_expired(&mut self) -> Vec<(u32, u16)> {
+        self.completed.retain(|_, completed| completed.elapsed() <= self.timeout);
+
+        let mut evicted = Vec::new();
+        self.pending.retain(|key, pending| {
+            let expired = pending.started.elapsed() > self.timeout;
+            if expired {
+                evicted.push(*key);
+            }
+            !expired
+        });
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(message_id: u16, fragment_index: u16, fragment_count: u16) -> FrameHeader {
+        FrameHeader::new(1, message_id, fragment_index, fragment_count)
+    }
+
+    #[test]
+    fn puts_fragments_back_in_order() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(30));
+        assert_eq!(reassembler.insert(header(1, 2, 3), b"c".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(1, 0, 3), b"a".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(1, 1, 3), b"b".to_vec()), Inserted::Complete(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn reports_duplicates() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(30));
+        assert_eq!(reassembler.insert(header(1, 0, 2), b"a".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(1, 0, 2), b"a".to_vec()), Inserted::Duplicate);
+        assert_eq!(reassembler.insert(header(1, 1, 2), b"b".to_vec()), Inserted::Complete(b"ab".to_vec()));
+        // Its message is whole already
+        assert_eq!(reassembler.insert(header(1, 1, 2), b"b".to_vec()), Inserted::Duplicate);
+    }
+
+    #[test]
+    fn reports_fragments_that_dont_fit() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(30));
+        assert_eq!(reassembler.insert(header(1, 0, 2), b"a".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(1, 1, 3), b"b".to_vec()), Inserted::Mismatch);
+        assert_eq!(reassembler.insert(header(2, 2, 2), b"b".to_vec()), Inserted::Mismatch);
+
+        // What came in of message 1 went with the mismatch, it starts over
+        assert_eq!(reassembler.insert(header(1, 1, 2), b"b".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(1, 0, 2), b"a".to_vec()), Inserted::Complete(b"ab".to_vec()));
+        // Out of range fragments don't start a message
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn refuses_messages_past_the_limits() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(30))
+            .with_max_fragments(4)
+            .with_max_pending(2);
+        assert_eq!(reassembler.insert(header(1, 0, 5), b"a".to_vec()), Inserted::Refused);
+        assert_eq!(reassembler.insert(header(1, 0, 4), b"a".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(2, 0, 4), b"a".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(header(3, 0, 4), b"a".to_vec()), Inserted::Refused);
+        // Messages already coming in still take fragments, and other sessions have their own room
+        assert_eq!(reassembler.insert(header(1, 1, 4), b"b".to_vec()), Inserted::Pending);
+        assert_eq!(reassembler.insert(FrameHeader::new(2, 3, 0, 1), b"a".to_vec()), Inserted::Complete(b"a".to_vec()));
+    }
+
+    #[test]
+    fn forgets_removed_sessions() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(30));
+        reassembler.insert(header(1, 0, 2), b"a".to_vec());
+        reassembler.insert(header(2, 0, 1), b"a".to_vec());
+        reassembler.insert(FrameHeader::new(2, 1, 0, 2), b"a".to_vec());
+
+        reassembler.remove_session(1);
+        assert_eq!(reassembler.pending.keys().collect::<Vec<_>>(), [&(2, 1)]);
+        assert_eq!(reassembler.insert(header(2, 0, 1), b"a".to_vec()), Inserted::Complete(b"a".to_vec()));
+    }
+
+    #[test]
+    fn evicts_after_the_timeout() {
+        let mut reassembler = Reassembler::new(Duration::ZERO);
+        reassembler.insert(header(1, 0, 2), b"a".to_vec());
+        reassembler.insert(header(2, 0, 1), b"a".to_vec());
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(reassembler.evict_expired(), [(1, 1)]);
+        // Completed messages are forgotten too, their fragments start over
+        assert_eq!(reassembler.insert(header(2, 0, 1), b"a".to_vec()), Inserted::Complete(b"a".to_vec()));
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use color_eyre::eyre::eyre;
+use crate::{FrameHeader, SEQUENCE_LEN};
+
+/// Message id and fragment index, what an acknowledgement names
+pub const ACK_LEN: usize = 4;
+/// Fragments either side keeps unacknowledged at once
+pub const SEND_WINDOW: usize = 8;
+/// How long a fragment waits on its acknowledgement before going out again
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Times a fragment is sent before its message is given up on
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// Names one fragment of one message, acknowledgements carry these
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FragmentId {
+    pub message_id: u16,
+    pub fragment_index: u16,
+}
+
+impl FragmentId {
+    pub fn new(message_id: u16, fragment_index: u16) -> Self {
+        Self {
+            message_id,
+            fragment_index,
+        }
+    }
+
+    pub fn of(header: &FrameHeader) -> Self {
+        Self::new(header.message_id, header.fragment_index)
+    }
+
+    pub fn to_bytes(self) -> [u8; ACK_LEN] {
+        let mut bytes = [0u8; ACK_LEN];
+        bytes[..2].copy_from_slice(&self.message_id.to_be_bytes());
+        bytes[2..].copy_from_slice(&self.fragment_index.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; ACK_LEN] = bytes.get(..ACK_LEN)?.try_into().ok()?;
+        Some(Self::new(
+            u16::from_be_bytes([bytes[0], bytes[1]]),
+            u16::from_be_bytes([bytes[2], bytes[3]]),
+        ))
+    }
+}
+
+/// Packs a batch of acknowledgements back to back
+pub fn acks_to_bytes(acks: &[FragmentId]) -> Vec<u8> {
+    acks.iter().flat_map(|ack| ack.to_bytes()).collect()
+}
+
+/// Reads acknowledgements from `acks_to_bytes`, a trailing partial one is ignored
+pub fn acks_from_bytes(bytes: &[u8]) -> Vec<FragmentId> {
+    bytes.chunks_exact(ACK_LEN).filter_map(FragmentId::from_bytes).collect()
+}
+
+/// What a poll seals: its sequence number, which guards against replays, then acknowledgements
+pub fn poll_to_bytes(sequence: u32, acks: &[FragmentId]) -> Vec<u8> {
+    [&sequence.to_be_bytes()[..], &acks_to_bytes(acks)].concat()
+}
+
+/// Reads a poll from `poll_to_bytes`, `None` when it's too short to hold a sequence number
+pub fn poll_from_bytes(bytes: &[u8]) -> Option<(u32, Vec<FragmentId>)> {
+    let sequence = bytes.get(..SEQUENCE_LEN)?.try_into().ok()?;
+    Some((u32::from_be_bytes(sequence), acks_from_bytes(&bytes[SEQUENCE_LEN..])))
+}
+
+/// A fragment that went out and hasn't been acknowledged yet
+struct InFlight<T> {
+    item: T,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+/// Sliding window over outgoing fragments. At most `window` of them are unacknowledged at once,
+/// the ones that go unacknowledged for longer than the timeout are handed out again
+pub struct SendWindow<T> {
+    window: usize,
+    timeout: Duration,
+    max_attempts: u32,
+    queued: VecDeque<(FragmentId, T)>,
+    in_flight: BTreeMap<FragmentId, InFlight<T>>,
+}
+
+impl<T: Clone> SendWindow<T> {
+    pub fn new(window: usize, timeout: Duration, max_attempts: u32) -> Self {
+        Self {
+            window: window.max(1),
+            timeout,
+            max_attempts,
+            queued: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, id: FragmentId, item: T) {
+        self.queued.push_back((id, item));
+    }
+
+    /// Nothing queued and nothing waiting on an acknowledgement
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty() && self.in_flight.is_empty()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Next fragment to send, the oldest timed out one first, then a new one if the window has room.
+    /// Once a fragment has gone out `max_attempts` times without being acknowledged its whole message is dropped and this fails
+    pub fn next_to_send(&mut self) -> color_eyre::Result<Option<(FragmentId, T)>> {
+        let now = Instant::now();
+        let expired = self.in_flight.iter_mut()
+            .filter(|(_, in_flight)| now.duration_since(in_flight.sent_at) >= self.timeout)
+            .min_by_key(|(_, in_flight)| in_flight.sent_at);
+        if let Some((id, in_flight)) = expired {
+            if in_flight.attempts >= self.max_attempts {
+                let (id, attempts) = (*id, in_flight.attempts);
+                self.drop_message(id.message_id);
+                return Err(eyre!("Fragment {} of message {} went unacknowledged after {} attempts",
+                                 id.fragment_index, id.message_id, attempts));
+            }
+            in_flight.attempts += 1;
+            in_flight.sent_at = now;
+            return Ok(Some((*id, in_flight.item.clone())));
+        }
+
+        if self.in_flight.len() >= self.window {
+            return Ok(None);
+        }
+        let Some((id, item)) = self.queued.pop_front() else {
+            return Ok(None);
+        };
+        self.in_flight.insert(id, InFlight {
+            item: item.clone(),
+            sent_at: now,
+            attempts: 1,
+        });
+
+        Ok(Some((id, item)))
+    }
+
+    /// Marks a fragment as delivered, returns false for ones that weren't waiting (duplicate or stray acks)
+    pub fn ack(&mut self, id: FragmentId) -> bool {
+        self.in_flight.remove(&id).is_some()
+    }
+
+    /// Forgets everything belonging to a message, used once it has been given up on
+    pub fn drop_message(&mut self, message_id: u16) {
+        self.queued.retain(|(id, _)| id.message_id != message_id);
+        self.in_flight.retain(|id, _| id.message_id != message_id);
+    }
+
+    /// When the oldest unacknowledged fragment is due to go out again
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.in_flight.values()
+            .map(|in_flight| in_flight.sent_at + self.timeout)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// Window over the fragments of message 1, each carrying its own index
+    fn window_of(fragments: u16, window: usize, timeout: Duration, max_attempts: u32) -> SendWindow<u16> {
+        let mut send_window = SendWindow::new(window, timeout, max_attempts);
+        for index in 0..fragments {
+            send_window.push(FragmentId::new(1, index), index);
+        }
+        send_window
+    }
+
+    #[test]
+    fn keeps_at_most_the_window_in_flight() {
+        let mut send_window = window_of(5, 3, TIMEOUT, MAX_ATTEMPTS);
+        for index in 0..3 {
+            assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, index), index)));
+        }
+        assert_eq!(send_window.next_to_send().unwrap(), None);
+        assert_eq!(send_window.in_flight(), 3);
+
+        // Each acknowledgement makes room for one more
+        assert!(send_window.ack(FragmentId::new(1, 1)));
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 3), 3)));
+        assert_eq!(send_window.next_to_send().unwrap(), None);
+    }
+
+    #[test]
+    fn resends_after_the_timeout() {
+        let mut send_window = window_of(2, 1, TIMEOUT, MAX_ATTEMPTS);
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 0), 0)));
+        assert_eq!(send_window.next_to_send().unwrap(), None);
+        let deadline = send_window.next_deadline().unwrap();
+
+        std::thread::sleep(TIMEOUT);
+        assert!(Instant::now() >= deadline);
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 0), 0)));
+        // Resending restarts the clock
+        assert_eq!(send_window.next_to_send().unwrap(), None);
+        assert!(send_window.next_deadline().unwrap() > deadline);
+    }
+
+    #[test]
+    fn resends_the_oldest_first() {
+        let mut send_window = window_of(2, 2, TIMEOUT, MAX_ATTEMPTS);
+        send_window.next_to_send().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        send_window.next_to_send().unwrap();
+
+        std::thread::sleep(TIMEOUT);
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 0), 0)));
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 1), 1)));
+    }
+
+    #[test]
+    fn drops_the_message_after_max_attempts() {
+        let mut send_window = window_of(3, 1, Duration::ZERO, MAX_ATTEMPTS);
+        send_window.push(FragmentId::new(2, 0), 10);
+
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 0), 0)));
+        }
+        assert!(send_window.next_to_send().is_err());
+
+        // The rest of message 1 goes with it, message 2 is next
+        assert_eq!(send_window.in_flight(), 0);
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(2, 0), 10)));
+    }
+
+    #[test]
+    fn duplicate_and_stray_acks_are_ignored() {
+        let mut send_window = window_of(2, 2, TIMEOUT, MAX_ATTEMPTS);
+        send_window.next_to_send().unwrap();
+
+        assert!(send_window.ack(FragmentId::new(1, 0)));
+        assert!(!send_window.ack(FragmentId::new(1, 0)));
+        // Queued but never sent
+        assert!(!send_window.ack(FragmentId::new(1, 1)));
+        assert!(!send_window.ack(FragmentId::new(7, 0)));
+
+        assert_eq!(send_window.next_to_send().unwrap(), Some((FragmentId::new(1, 1), 1)));
+        assert!(send_window.ack(FragmentId::new(1, 1)));
+        assert!(send_window.is_empty());
+        assert_eq!(send_window.next_deadline(), None);
+    }
+
+    #[test]
+    fn poll_round_trip() {
+        let acks = [FragmentId::new(1, 0), FragmentId::new(0xffff, 3)];
+        let poll = poll_to_bytes(0xdead_beef, &acks);
+        assert_eq!(poll_from_bytes(&poll), Some((0xdead_beef, acks.to_vec())));
+        assert_eq!(poll_from_bytes(&poll[..SEQUENCE_LEN - 1]), None);
+        // A trailing partial acknowledgement is ignored
+        assert_eq!(poll_from_bytes(&poll[..poll.len() - 1]), Some((0xdead_beef, acks[..1].to_vec())));
+    }
+}
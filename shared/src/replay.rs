@@ -0,0 +1,185 @@
+/// Sequence numbers remembered behind the highest one seen, older ones are taken for replays
+pub const REPLAY_WINDOW: u64 = 128;
+/// Poll sequence number, sealed in front of the poll's acknowledgements
+pub const SEQUENCE_LEN: usize = 4;
+
+/// How a sequence number compares to the ones seen before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    New,
+    /// Seen before, still within the window
+    Duplicate,
+    /// So far behind the newest one that the window can't tell anymore
+    Stale,
+}
+
+/// Sliding window over a session's sequence numbers, like IPsec's anti-replay window (RFC 4303).
+/// Numbers are checked before a packet is acted on and only marked once it has been authenticated
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` is set once `highest - i` has been marked
+    seen: u128,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(&self, sequence: u64) -> Freshness {
+        let Some(highest) = self.highest else {
+            return Freshness::New;
+        };
+        if sequence > highest {
+            return Freshness::New;
+        }
+
+        let behind = highest - sequence;
+        if behind >= REPLAY_WINDOW {
+            Freshness::Stale
+        } else if self.seen & (1 << behind) != 0 {
+            Freshness::Duplicate
+        } else {
+            Freshness::New
+        }
+    }
+
+    /// Marks a number as seen, sliding the window forward when it's the newest yet.
+    /// Returns how fresh it was, only new numbers get marked
+    pub fn mark(&mut self, sequence: u64) -> Freshness {
+        let freshness = self.check(sequence);
+        if freshness != Freshness::New {
+            return freshness;
+        }
+
+        match self.highest {
+            Some(highest) if sequence <= highest => self.seen |= 1 << (highest - sequence),
+            Some(highest) => {
+                let ahead = sequence - highest;
+                self.seen = if ahead >= REPLAY_WINDOW { 1 } else { (self.seen << ahead) | 1 };
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(sequence);
+            }
+        }
+
+        Freshness::New
+    }
+
+    /// Places a 16-bit id that wraps around, like a message id, on the window's line: whichever of its
+    /// wrapped values lies closest to the highest number seen
+    pub fn extend(&self, id: u16) -> u64 {
+        let Some(highest) = self.highest else {
+            return id as u64;
+        };
+
+        let base = highest & !0xffff;
+        [base.checked_sub(0x10000), Some(base), base.checked_add(0x10000)]
+            .into_iter()
+            .flatten()
+            .map(|base| base | id as u64)
+            .min_by_key(|candidate| candidate.abs_diff(highest))
+            .unwrap_or(id as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_then_duplicate() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check(5), Freshness::New);
+        assert_eq!(window.mark(5), Freshness::New);
+        assert_eq!(window.check(5), Freshness::Duplicate);
+        assert_eq!(window.mark(5), Freshness::Duplicate);
+
+        // Behind the highest but never seen
+        assert_eq!(window.mark(3), Freshness::New);
+        assert_eq!(window.mark(3), Freshness::Duplicate);
+        assert_eq!(window.check(4), Freshness::New);
+        assert_eq!(window.mark(6), Freshness::New);
+        assert_eq!(window.check(5), Freshness::Duplicate);
+    }
+
+    #[test]
+    fn stale_at_exactly_the_window() {
+        let mut window = ReplayWindow::new();
+        window.mark(REPLAY_WINDOW + 10);
+
+        assert_eq!(window.check(11), Freshness::New);
+        assert_eq!(window.check(10), Freshness::Stale);
+        assert_eq!(window.mark(10), Freshness::Stale);
+        assert_eq!(window.check(0), Freshness::Stale);
+
+        // The oldest one the window still tracks
+        assert_eq!(window.mark(11), Freshness::New);
+        assert_eq!(window.mark(11), Freshness::Duplicate);
+    }
+
+    #[test]
+    fn jumps_of_the_window_or_more_start_over() {
+        for jump in [REPLAY_WINDOW, REPLAY_WINDOW + 1, 10 * REPLAY_WINDOW] {
+            let mut window = ReplayWindow::new();
+            for sequence in 0..10 {
+                window.mark(sequence);
+            }
+
+            assert_eq!(window.mark(9 + jump), Freshness::New);
+            assert_eq!(window.check(9 + jump), Freshness::Duplicate);
+            // Nothing from before the jump is still marked, whatever lies within the window again is new
+            for behind in 1..REPLAY_WINDOW.min(jump) {
+                assert_eq!(window.check(9 + jump - behind), Freshness::New, "jump {jump}, behind {behind}");
+            }
+        }
+    }
+
+    #[test]
+    fn jumps_within_the_window_keep_what_was_seen() {
+        let mut window = ReplayWindow::new();
+        window.mark(0);
+        window.mark(REPLAY_WINDOW - 1);
+
+        assert_eq!(window.check(0), Freshness::Duplicate);
+        window.mark(REPLAY_WINDOW);
+        assert_eq!(window.check(0), Freshness::Stale);
+        assert_eq!(window.check(REPLAY_WINDOW - 1), Freshness::Duplicate);
+    }
+
+    #[test]
+    fn extend_across_the_wrap() {
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.extend(0xfff0), 0xfff0);
+
+        window.mark(window.extend(0xfff0));
+        // Just past the wrap goes on from there instead of back to the start
+        assert_eq!(window.extend(0x0005), 0x1_0005);
+        window.mark(window.extend(0x0005));
+
+        // Just before the wrap stays behind it
+        assert_eq!(window.extend(0xfff0), 0xfff0);
+        assert_eq!(window.check(window.extend(0xfff0)), Freshness::Duplicate);
+        assert_eq!(window.check(window.extend(0xfff8)), Freshness::New);
+        assert_eq!(window.extend(0x0006), 0x1_0006);
+    }
+
+    #[test]
+    fn extend_keeps_counting_over_many_wraps() {
+        let mut window = ReplayWindow::new();
+        let mut id = 0u16;
+        for expected in 0..3 * 0x10000u64 {
+            let sequence = window.extend(id);
+            assert_eq!(sequence, expected);
+            assert_eq!(window.mark(sequence), Freshness::New);
+            id = id.wrapping_add(1);
+        }
+
+        // A replay from a lap ago is far behind, not new
+        assert_eq!(window.check(window.extend(id.wrapping_sub(1))), Freshness::Duplicate);
+        assert_eq!(window.check(window.extend(id.wrapping_sub(REPLAY_WINDOW as u16 + 1))), Freshness::Stale);
+    }
+}